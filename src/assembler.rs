@@ -0,0 +1,634 @@
+use crate::error::Error;
+use crate::library::{Command, CommandParamType};
+use crate::loader::Header;
+use crate::platform;
+use crate::types::{self, HEX_BLOCK, INVALID_OPCODE, NOT_PREFIX};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::{fs, path};
+
+/// File with the header values written next to the disassembly
+pub const MANIFEST_FILE: &str = "header.cfg";
+
+const MODEL_NAME_SIZE: usize = 24;
+const STR8_SIZE: usize = 8;
+/// Size of a GOTO with a NUM32 offset that opens each header segment
+const SEGMENT_JUMP_SIZE: u32 = 7;
+
+/// Header values that are not part of the disassembly itself
+pub struct Manifest {
+    pub target_game: u8,
+    /// size of the global variable space in bytes, the space is zero-filled on assembly
+    pub globals_size: u32,
+    pub models: Vec<String>,
+    pub exclusive_missions: u16,
+    /// file with the MAIN script
+    pub main: String,
+    /// mission indices with their files in the file order,
+    /// `None` stands for an empty mission at the end of file
+    pub missions: Vec<(usize, Option<String>)>,
+}
+
+impl Manifest {
+    /// Describes the header using the names of the files the scripts were written to
+    pub fn new(header: &Header, main: String, missions: &HashMap<usize, String>) -> Self {
+        let mut offsets: Vec<(u32, usize)> = header
+            .missions
+            .offsets
+            .iter()
+            .enumerate()
+            .map(|(index, &offset)| (offset, index))
+            .collect();
+        offsets.sort_unstable();
+
+        // missions sharing an offset were disassembled once, under the lowest index
+        let mut files: HashMap<u32, String> = HashMap::new();
+        let missions = offsets
+            .into_iter()
+            .map(|(offset, index)| {
+                let file = match missions.get(&index) {
+                    Some(file) => Some(file.clone()),
+                    None => files.get(&offset).cloned(),
+                };
+                if let Some(file) = &file {
+                    files.insert(offset, file.clone());
+                }
+                (index, file)
+            })
+            .collect();
+
+        Self {
+            target_game: header.globals.target_game,
+            globals_size: header.globals.size,
+            models: header.models.clone(),
+            exclusive_missions: header.missions.exclusive_missions,
+            main,
+            missions,
+        }
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "game {}", self.target_game)?;
+        writeln!(out, "globals {}", self.globals_size)?;
+        for model in &self.models {
+            writeln!(out, "model {}", model)?;
+        }
+        writeln!(out, "exclusive {}", self.exclusive_missions)?;
+        writeln!(out, "main {}", self.main)?;
+        for (index, file) in &self.missions {
+            writeln!(out, "mission {} {}", index, file.as_deref().unwrap_or("-"))?;
+        }
+        Ok(())
+    }
+
+    pub fn read(file_name: &str) -> Result<Self, Error> {
+        let content = fs::read_to_string(file_name).map_err(|e| Error::io(file_name, e))?;
+        let mut manifest = Self {
+            target_game: 0,
+            globals_size: 0,
+            models: vec![],
+            exclusive_missions: 0,
+            main: String::new(),
+            missions: vec![],
+        };
+
+        for (line_index, line) in content.lines().enumerate() {
+            let line_err = |message: &str| Error::Assembly {
+                path: String::from(file_name),
+                line: line_index + 1,
+                message: String::from(message),
+            };
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "" => {}
+                "game" => {
+                    manifest.target_game = value.parse().map_err(|_| line_err("Invalid game"))?
+                }
+                "globals" => {
+                    manifest.globals_size = value
+                        .parse()
+                        .map_err(|_| line_err("Invalid variable space size"))?
+                }
+                "model" => manifest.models.push(String::from(value)),
+                "exclusive" => {
+                    manifest.exclusive_missions = value
+                        .parse()
+                        .map_err(|_| line_err("Invalid number of exclusive missions"))?
+                }
+                "main" => manifest.main = String::from(value),
+                "mission" => {
+                    let (index, file) = value
+                        .split_once(' ')
+                        .ok_or_else(|| line_err("Invalid mission entry"))?;
+                    let index = index
+                        .parse()
+                        .map_err(|_| line_err("Invalid mission index"))?;
+                    let file = match file {
+                        "-" => None,
+                        file => Some(String::from(file)),
+                    };
+                    manifest.missions.push((index, file));
+                }
+                _ => return Err(line_err(&format!("Unknown key {}", key))),
+            }
+        }
+
+        if manifest.main.is_empty() {
+            return Err(Error::Assembly {
+                path: String::from(file_name),
+                line: 0,
+                message: String::from("MAIN script is not specified"),
+            });
+        }
+        Ok(manifest)
+    }
+}
+
+/// Reference to a label that is resolved once the whole file is encoded
+struct LabelRef {
+    position: usize,
+    label: i32,
+}
+
+/// Compiles the text written by `IR::write` back into a GTA III or Vice City script file
+pub struct Assembler<'a> {
+    game: &'a platform::Game,
+    definitions: &'a HashMap<types::Opcode, Command>,
+    commands: HashMap<&'a str, types::Opcode>,
+    segment_jump: types::Opcode,
+}
+
+impl<'a> Assembler<'a> {
+    pub fn new(
+        game: &'a platform::Game,
+        definitions: &'a HashMap<types::Opcode, Command>,
+    ) -> Result<Self, Error> {
+        if let platform::Game::SA | platform::Game::LCS | platform::Game::VCS = game {
+            return Err(Error::Unsupported(format!(
+                "Assembling is not supported for {:?}",
+                game
+            )));
+        }
+
+        // names shared by several commands are written with the raw opcode,
+        // without it the lowest id wins
+        let mut commands: HashMap<&str, types::Opcode> = HashMap::new();
+        for (id, command) in definitions {
+            commands
+                .entry(command.name.as_str())
+                .and_modify(|e| *e = (*e).min(*id))
+                .or_insert(*id);
+        }
+        let segment_jump = definitions
+            .iter()
+            .find(|(_id, c)| c.attrs.is_segment)
+            .map(|(id, _)| *id)
+            .ok_or_else(|| Error::Definitions {
+                message: String::from("Can't find a command with attribute is_segment"),
+            })?;
+
+        Ok(Self {
+            game,
+            definitions,
+            commands,
+            segment_jump,
+        })
+    }
+
+    /// Builds the script file from the manifest and the scripts in the given directory
+    pub fn run(&self, dir: &path::Path) -> Result<Vec<u8>, Error> {
+        let manifest_file = dir.join(MANIFEST_FILE).display().to_string();
+        let manifest = Manifest::read(&manifest_file)?;
+
+        let num_missions = manifest.missions.len();
+        let globals_end = SEGMENT_JUMP_SIZE + 1 + manifest.globals_size;
+        let models_end = globals_end
+            + SEGMENT_JUMP_SIZE
+            + 1
+            + 4
+            + (manifest.models.len() * MODEL_NAME_SIZE) as u32;
+        let main_start = models_end + SEGMENT_JUMP_SIZE + 1 + 12 + num_missions as u32 * 4;
+
+        let mut out = vec![];
+        self.write_segment_jump(&mut out, globals_end);
+        out.push(manifest.target_game);
+        out.resize(globals_end as usize, 0);
+
+        self.write_segment_jump(&mut out, models_end);
+        out.push(0);
+        out.write_u32::<LittleEndian>(manifest.models.len() as u32)
+            .unwrap();
+        for model in &manifest.models {
+            if model.len() > MODEL_NAME_SIZE {
+                return Err(Error::Assembly {
+                    path: manifest_file,
+                    line: 0,
+                    message: format!("Model name {} is too long", model),
+                });
+            }
+            let mut name = model.as_bytes().to_vec();
+            name.resize(MODEL_NAME_SIZE, 0);
+            out.extend(name);
+        }
+
+        let main_file = dir.join(&manifest.main).display().to_string();
+        let (main, main_labels) =
+            self.assemble_file(&main_file, main_start, &manifest.models, None)?;
+        let main_size = main_start + main.len() as u32;
+
+        let mut offsets = vec![None; num_missions];
+        let mut file_offsets: HashMap<&str, u32> = HashMap::new();
+        let mut missions = vec![];
+        let mut largest_mission = 0;
+        let mut end = main_size;
+        for (index, file) in &manifest.missions {
+            let slot = offsets.get_mut(*index).ok_or_else(|| Error::Assembly {
+                path: manifest_file.clone(),
+                line: 0,
+                message: format!("Mission index {} is out of range", index),
+            })?;
+            let offset = match file {
+                None => end,
+                Some(file) => match file_offsets.get(file.as_str()) {
+                    Some(offset) => *offset,
+                    None => {
+                        let file_name = dir.join(file).display().to_string();
+                        let (mission, _) = self.assemble_file(
+                            &file_name,
+                            0,
+                            &manifest.models,
+                            Some(&main_labels),
+                        )?;
+                        let offset = end;
+                        end += mission.len() as u32;
+                        largest_mission = largest_mission.max(mission.len() as u32);
+                        file_offsets.insert(file, offset);
+                        missions.push(mission);
+                        offset
+                    }
+                },
+            };
+            *slot = Some(offset);
+        }
+
+        self.write_segment_jump(&mut out, main_start);
+        out.push(0);
+        out.write_u32::<LittleEndian>(main_size).unwrap();
+        out.write_u32::<LittleEndian>(largest_mission).unwrap();
+        out.write_u16::<LittleEndian>(num_missions as u16).unwrap();
+        out.write_u16::<LittleEndian>(manifest.exclusive_missions)
+            .unwrap();
+        for (index, offset) in offsets.into_iter().enumerate() {
+            let offset = offset.ok_or_else(|| Error::Assembly {
+                path: manifest_file.clone(),
+                line: 0,
+                message: format!("Mission {} is missing", index),
+            })?;
+            out.write_u32::<LittleEndian>(offset).unwrap();
+        }
+
+        out.extend(main);
+        for mission in missions {
+            out.extend(mission);
+        }
+        Ok(out)
+    }
+
+    fn write_segment_jump(&self, out: &mut Vec<u8>, destination: u32) {
+        out.write_u16::<LittleEndian>(self.segment_jump).unwrap();
+        out.push(0x01);
+        out.write_u32::<LittleEndian>(destination).unwrap();
+    }
+
+    fn assemble_file(
+        &self,
+        file_name: &str,
+        base_offset: u32,
+        models: &[String],
+        main_labels: Option<&HashMap<u32, u32>>,
+    ) -> Result<(Vec<u8>, HashMap<u32, u32>), Error> {
+        let content = fs::read_to_string(file_name).map_err(|e| Error::io(file_name, e))?;
+        self.assemble_source(file_name, &content, base_offset, models, main_labels)
+    }
+
+    /// Encodes a single script and returns it along with the new positions of its labels.
+    /// Positive label references point into MAIN, negative ones are relative to the mission start.
+    /// `main_labels` is only given for missions
+    pub(crate) fn assemble_source(
+        &self,
+        file_name: &str,
+        content: &str,
+        base_offset: u32,
+        models: &[String],
+        main_labels: Option<&HashMap<u32, u32>>,
+    ) -> Result<(Vec<u8>, HashMap<u32, u32>), Error> {
+        let mut out = vec![];
+        let mut labels: HashMap<u32, u32> = HashMap::new();
+        let mut label_refs = vec![];
+
+        for (line_index, line) in content.lines().enumerate() {
+            let line_err = |message: String| Error::Assembly {
+                path: String::from(file_name),
+                line: line_index + 1,
+                message,
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+            if let Some(label) = line.strip_prefix(':') {
+                let label = label
+                    .parse()
+                    .map_err(|_| line_err(format!("Invalid label {}", label)))?;
+                labels.insert(label, base_offset + out.len() as u32);
+                continue;
+            }
+            // the offset in braces is informational
+            let line = match line.split_once('}') {
+                Some((_, rest)) if line.starts_with('{') => rest,
+                _ => line,
+            };
+            let mut refs = vec![];
+            self.write_instruction(&mut out, &mut refs, &tokenize(line), models)
+                .map_err(line_err)?;
+            label_refs.extend(refs.into_iter().map(|r| (line_index + 1, r)));
+        }
+
+        for (line, LabelRef { position, label }) in label_refs {
+            let target = match main_labels {
+                None if label >= 0 => labels.get(&(label as u32)).map(|&t| t as i32),
+                Some(main_labels) if label >= 0 => {
+                    main_labels.get(&(label as u32)).map(|&t| t as i32)
+                }
+                Some(_) => labels.get(&label.unsigned_abs()).map(|&t| -(t as i32)),
+                None => None,
+            };
+            // the old offset would point to whatever moved there in the edited source
+            let value = target.ok_or_else(|| Error::Assembly {
+                path: String::from(file_name),
+                line,
+                message: format!("Unknown label {}", label),
+            })?;
+            out[position..position + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        Ok((out, labels))
+    }
+
+    fn write_instruction(
+        &self,
+        out: &mut Vec<u8>,
+        label_refs: &mut Vec<LabelRef>,
+        tokens: &[&str],
+        models: &[String],
+    ) -> Result<(), String> {
+        let (negated, tokens) = match tokens {
+            [NOT_PREFIX, rest @ ..] => (true, rest),
+            _ => (false, tokens),
+        };
+        // the raw opcode of a command with a stray negation bit (`8001: WAIT 0`)
+        // or a name shared by several commands (`0A93: TERMINATE_THIS_CUSTOM_SCRIPT`)
+        let (raw, tokens) = match tokens {
+            [first, rest @ ..] if first.ends_with(':') => {
+                let raw = &first[..first.len() - 1];
+                let raw =
+                    u16::from_str_radix(raw, 16).map_err(|_| format!("Invalid opcode {}", raw))?;
+                (Some(raw), rest)
+            }
+            _ => (None, tokens),
+        };
+        let Some((&name, params)) = tokens.split_first() else {
+            return Err(String::from("Missing command name"));
+        };
+
+        if name == INVALID_OPCODE || name == HEX_BLOCK {
+            // the ASCII view of the data block is a comment
+            for param in params.iter().take_while(|&&p| p != "//") {
+                let byte =
+                    u8::from_str_radix(param, 16).map_err(|_| format!("Invalid byte {}", param))?;
+                out.push(byte);
+            }
+            return Ok(());
+        }
+
+        let opcode = *self
+            .commands
+            .get(name)
+            .ok_or_else(|| format!("Unknown command {}", name))?;
+        let opcode = match raw {
+            Some(raw) => self
+                .definitions
+                .get(&(raw & 0x7FFF))
+                .filter(|def| def.name == name)
+                .map(|_| raw)
+                .ok_or_else(|| format!("Opcode {:04X} does not match {}", raw, name))?,
+            None if negated => opcode | 0x8000,
+            None => opcode,
+        };
+        let def = &self.definitions[&(opcode & 0x7FFF)];
+        out.write_u16::<LittleEndian>(opcode).unwrap();
+
+        let mut params = params.iter();
+        for param in def.input.iter().chain(def.output.iter()) {
+            if param.r#type == CommandParamType::Arguments {
+                for token in params.by_ref() {
+                    self.write_param(out, label_refs, token, &param.r#type, models)?;
+                }
+                // the parser stops reading the instruction after the argument list
+                out.push(0x00);
+                return Ok(());
+            }
+            let token = params
+                .next()
+                .ok_or_else(|| format!("Missing parameter {} of {}", param.name, name))?;
+            self.write_param(out, label_refs, token, &param.r#type, models)?;
+        }
+
+        match params.next() {
+            Some(token) => Err(format!("Unexpected parameter {} of {}", token, name)),
+            None => Ok(()),
+        }
+    }
+
+    fn write_param(
+        &self,
+        out: &mut Vec<u8>,
+        label_refs: &mut Vec<LabelRef>,
+        token: &str,
+        param_type: &CommandParamType,
+        models: &[String],
+    ) -> Result<(), String> {
+        let invalid = || format!("Invalid parameter {}", token);
+
+        if let Some(s) = token.strip_prefix('"') {
+            // the bytes after the terminator follow the text in brackets, `"CSPLAY"[CC]`
+            let (s, tail) = s.rsplit_once('"').ok_or_else(invalid)?;
+            let mut buf = unescape(s).ok_or_else(invalid)?;
+            if let Some(hex) = tail.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+                buf.push(0);
+                buf.extend(parse_hex(hex).ok_or_else(invalid)?);
+            } else if !tail.is_empty() {
+                return Err(invalid());
+            }
+            if buf.is_empty() || buf.len() > STR8_SIZE {
+                return Err(format!("String {} must be 1 to 8 characters long", token));
+            }
+            buf.resize(STR8_SIZE, 0);
+            out.extend(buf);
+        } else if let Some(index) = token.strip_prefix("gvar_") {
+            out.push(0x02);
+            out.write_u16::<LittleEndian>(index.parse().map_err(|_| invalid())?)
+                .unwrap();
+        } else if let Some(index) = token.strip_prefix("lvar_") {
+            out.push(0x03);
+            out.write_u16::<LittleEndian>(index.parse().map_err(|_| invalid())?)
+                .unwrap();
+        } else if let Some(model) = token.strip_prefix('#') {
+            let index = models
+                .iter()
+                .position(|m| m == model)
+                .ok_or_else(|| format!("Unknown model {}", model))?;
+            write_int(out, -(index as i32));
+        } else if let Ok(value) = token.parse::<i32>() {
+            if param_type == &CommandParamType::Label {
+                out.push(0x01);
+                label_refs.push(LabelRef {
+                    position: out.len(),
+                    label: value,
+                });
+                out.write_i32::<LittleEndian>(0).unwrap();
+            } else {
+                write_int(out, value);
+            }
+        } else if let Ok(value) = token.parse::<f32>() {
+            out.push(0x06);
+            match self.game {
+                platform::Game::GTA3 => out
+                    .write_i16::<LittleEndian>((value * 16.0).round() as i16)
+                    .unwrap(),
+                _ => out.write_f32::<LittleEndian>(value).unwrap(),
+            }
+        } else {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+}
+
+/// Integers take the smallest of NUM8, NUM16 and NUM32
+fn write_int(out: &mut Vec<u8>, value: i32) {
+    if let Ok(value) = i8::try_from(value) {
+        out.push(0x04);
+        out.write_i8(value).unwrap();
+    } else if let Ok(value) = i16::try_from(value) {
+        out.push(0x05);
+        out.write_i16::<LittleEndian>(value).unwrap();
+    } else {
+        out.push(0x01);
+        out.write_i32::<LittleEndian>(value).unwrap();
+    }
+}
+
+/// Reverses the escapes of string literals (`\"`, `\\` and `\xNN`)
+fn unescape(s: &str) -> Option<Vec<u8>> {
+    let mut buf = vec![];
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            buf.push(b);
+            continue;
+        }
+        match bytes.next()? {
+            b'x' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                buf.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            c => buf.push(c),
+        }
+    }
+    Some(buf)
+}
+
+/// Bytes of a hex string without separators (`00CC`)
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Splits a line by spaces keeping quoted strings intact
+fn tokenize(line: &str) -> Vec<&str> {
+    let mut tokens = vec![];
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let end = match rest.strip_prefix('"') {
+            Some(s) => {
+                let mut escaped = false;
+                s.char_indices()
+                    .find(|&(_, c)| {
+                        let closing = c == '"' && !escaped;
+                        escaped = c == '\\' && !escaped;
+                        closing
+                    })
+                    // the bytes after the terminator of a string follow the closing quote
+                    .map(|(i, _)| i + 2 + rest[i + 2..].find(' ').unwrap_or(rest.len() - i - 2))
+                    .unwrap_or(rest.len())
+            }
+            None => rest.find(' ').unwrap_or(rest.len()),
+        };
+        tokens.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(id: &str, name: &str, attrs: &str) -> (types::Opcode, Command) {
+        let command: Command = serde_json::from_str(&format!(
+            r#"{{"id": "{}", "name": "{}", "num_params": 0, "attrs": {{{}}}}}"#,
+            id, name, attrs
+        ))
+        .unwrap();
+        (types::Opcode::from_str_radix(id, 16).unwrap(), command)
+    }
+
+    fn assemble(assembler: &Assembler, line: &str) -> Result<Vec<u8>, String> {
+        let mut out = vec![];
+        assembler.write_instruction(&mut out, &mut vec![], &tokenize(line), &[])?;
+        Ok(out)
+    }
+
+    #[test]
+    fn keeps_the_opcode_of_aliases() {
+        let definitions = HashMap::from([
+            command("0002", "GOTO", r#""is_segment": true"#),
+            command("05DC", "TERMINATE_THIS_CUSTOM_SCRIPT", ""),
+            command("0A93", "TERMINATE_THIS_CUSTOM_SCRIPT", ""),
+        ]);
+        let assembler = Assembler::new(&platform::Game::VC, &definitions).unwrap();
+
+        let name = "TERMINATE_THIS_CUSTOM_SCRIPT";
+        assert_eq!(
+            assemble(&assembler, &format!("0A93: {}", name)),
+            Ok(vec![0x93, 0x0A])
+        );
+        assert_eq!(
+            assemble(&assembler, &format!("05DC: {}", name)),
+            Ok(vec![0xDC, 0x05])
+        );
+        // without the raw opcode the lowest id wins
+        assert_eq!(assemble(&assembler, name), Ok(vec![0xDC, 0x05]));
+        assert_eq!(
+            assemble(&assembler, &format!("0002: {}", name)),
+            Err(format!("Opcode 0002 does not match {}", name))
+        );
+    }
+}
@@ -0,0 +1,412 @@
+use crate::types::{Instruction, ScriptType};
+
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Range;
+
+const GOTO: &str = "GOTO";
+const GOTO_IF_FALSE: &str = "GOTO_IF_FALSE";
+const GOTO_IF_TRUE: &str = "GOTO_IF_TRUE";
+const GOSUB: &str = "GOSUB";
+const RETURN: &str = "RETURN";
+const TERMINATE_THIS_SCRIPT: &str = "TERMINATE_THIS_SCRIPT";
+const TERMINATE_THIS_CUSTOM_SCRIPT: &str = "TERMINATE_THIS_CUSTOM_SCRIPT";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// unconditional GOTO
+    Jump,
+    /// conditional jump taken
+    Branch,
+    /// GOSUB to the subroutine
+    Call,
+    /// next instruction, including the return point of a GOSUB
+    FallThrough,
+    /// RETURN back to the instruction after a GOSUB reaching it
+    Return,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug)]
+pub struct BasicBlock {
+    /// indices of the block instructions in the IR
+    pub instructions: Range<usize>,
+}
+
+/// How the last instruction of a block passes control
+pub(super) enum Flow {
+    Next,
+    Jump(Option<u32>),
+    Branch(Option<u32>),
+    Call(Option<u32>),
+    Return,
+    Exit,
+}
+
+impl Flow {
+    /// Whether the next instruction runs after this one, a GOSUB comes back to it
+    pub(super) fn falls_through(&self) -> bool {
+        matches!(self, Flow::Next | Flow::Branch(_) | Flow::Call(_))
+    }
+}
+
+/// Control flow graph of a script. The first block is the entry point, blocks ending
+/// with TERMINATE_THIS_SCRIPT or a RETURN no GOSUB reaches are exits
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    pub edges: Vec<Edge>,
+    pub exits: Vec<usize>,
+    /// indices of the edges leaving and entering every block
+    outgoing: Vec<Vec<usize>>,
+    incoming: Vec<Vec<usize>>,
+}
+
+impl Cfg {
+    pub fn new(instructions: &[Instruction], script_type: ScriptType) -> Self {
+        let flows: Vec<Flow> = instructions
+            .iter()
+            .map(|i| get_flow(i, script_type))
+            .collect();
+        let index_of: HashMap<u32, usize> = instructions
+            .iter()
+            .enumerate()
+            .map(|(index, i)| (i.offset, index))
+            .collect();
+
+        // a block starts at the entry, at every jump target and after every transfer of control
+        let mut leaders = BTreeSet::new();
+        if !instructions.is_empty() {
+            leaders.insert(0);
+        }
+        for (index, flow) in flows.iter().enumerate() {
+            let target = match flow {
+                Flow::Next => continue,
+                Flow::Jump(target) | Flow::Branch(target) | Flow::Call(target) => *target,
+                Flow::Return | Flow::Exit => None,
+            };
+            if let Some(&target) = target.and_then(|t| index_of.get(&t)) {
+                leaders.insert(target);
+            }
+            if index + 1 < instructions.len() {
+                leaders.insert(index + 1);
+            }
+        }
+
+        let leaders: Vec<usize> = leaders.into_iter().collect();
+        let blocks: Vec<BasicBlock> = leaders
+            .iter()
+            .enumerate()
+            .map(|(n, &start)| BasicBlock {
+                instructions: start..leaders.get(n + 1).copied().unwrap_or(instructions.len()),
+            })
+            .collect();
+        let block_of: HashMap<usize, usize> = leaders
+            .iter()
+            .enumerate()
+            .map(|(n, &start)| (start, n))
+            .collect();
+
+        let mut cfg = Self {
+            outgoing: vec![vec![]; blocks.len()],
+            incoming: vec![vec![]; blocks.len()],
+            blocks,
+            edges: vec![],
+            exits: vec![],
+        };
+        for from in 0..cfg.blocks.len() {
+            let last = cfg.blocks[from].instructions.end - 1;
+            let next = (from + 1 < cfg.blocks.len()).then_some(from + 1);
+            // targets outside of this script (e.g. a mission calling MAIN) have no block
+            let block_at = |target: &Option<u32>| {
+                target
+                    .and_then(|t| index_of.get(&t))
+                    .and_then(|index| block_of.get(index))
+                    .copied()
+            };
+            let mut add = |to: Option<usize>, kind| {
+                if let Some(to) = to {
+                    cfg.add_edge(Edge { from, to, kind })
+                }
+            };
+            match &flows[last] {
+                Flow::Next => add(next, EdgeKind::FallThrough),
+                Flow::Jump(target) => add(block_at(target), EdgeKind::Jump),
+                Flow::Branch(target) => {
+                    add(block_at(target), EdgeKind::Branch);
+                    add(next, EdgeKind::FallThrough);
+                }
+                Flow::Call(target) => {
+                    add(block_at(target), EdgeKind::Call);
+                    add(next, EdgeKind::FallThrough);
+                }
+                Flow::Return | Flow::Exit => {}
+            }
+        }
+
+        cfg.add_returns(&flows);
+        cfg.exits = (0..cfg.blocks.len())
+            .filter(|&block| {
+                let last = cfg.blocks[block].instructions.end - 1;
+                matches!(flows[last], Flow::Return | Flow::Exit)
+                    && cfg.successors(block).next().is_none()
+            })
+            .collect();
+        cfg
+    }
+
+    /// Links every RETURN reached from a GOSUB target back to the block after the GOSUB.
+    /// Nested GOSUBs are stepped over through their return point
+    fn add_returns(&mut self, flows: &[Flow]) {
+        let calls: Vec<(usize, usize)> = self
+            .edges
+            .iter()
+            .filter(|e| e.kind == EdgeKind::Call)
+            .map(|e| (e.from, e.to))
+            .collect();
+        let mut returns = vec![];
+        for (call, subroutine) in calls {
+            let Some(back) = self
+                .successors(call)
+                .find(|e| e.kind == EdgeKind::FallThrough)
+                .map(|e| e.to)
+            else {
+                continue;
+            };
+            let mut visited = BTreeSet::from([subroutine]);
+            let mut pending = vec![subroutine];
+            while let Some(block) = pending.pop() {
+                let last = self.blocks[block].instructions.end - 1;
+                if let Flow::Return = flows[last] {
+                    returns.push(Edge {
+                        from: block,
+                        to: back,
+                        kind: EdgeKind::Return,
+                    });
+                }
+                for edge in self.successors(block).filter(|e| e.kind != EdgeKind::Call) {
+                    if visited.insert(edge.to) {
+                        pending.push(edge.to);
+                    }
+                }
+            }
+        }
+        for edge in returns {
+            self.add_edge(edge);
+        }
+    }
+
+    fn add_edge(&mut self, edge: Edge) {
+        self.outgoing[edge.from].push(self.edges.len());
+        self.incoming[edge.to].push(self.edges.len());
+        self.edges.push(edge);
+    }
+
+    pub fn successors(&self, block: usize) -> impl Iterator<Item = &Edge> {
+        self.outgoing[block].iter().map(|&edge| &self.edges[edge])
+    }
+
+    pub fn predecessors(&self, block: usize) -> impl Iterator<Item = &Edge> {
+        self.incoming[block].iter().map(|&edge| &self.edges[edge])
+    }
+
+    /// Block the instruction at the index belongs to
+    pub fn block_of(&self, index: usize) -> Option<usize> {
+        let block = self
+            .blocks
+            .partition_point(|b| b.instructions.start <= index)
+            .checked_sub(1)?;
+        self.blocks[block]
+            .instructions
+            .contains(&index)
+            .then_some(block)
+    }
+
+    /// Index of the instruction the GOTO or conditional jump at the index leads to
+    pub fn jump_target(&self, index: usize) -> Option<usize> {
+        self.target(index, |kind| {
+            matches!(kind, EdgeKind::Jump | EdgeKind::Branch)
+        })
+    }
+
+    /// Index of the first instruction of the subroutine the GOSUB at the index calls
+    pub fn call_target(&self, index: usize) -> Option<usize> {
+        self.target(index, |kind| kind == EdgeKind::Call)
+    }
+
+    fn target(&self, index: usize, is_kind: impl Fn(EdgeKind) -> bool) -> Option<usize> {
+        let block = self.block_of(index)?;
+        if self.blocks[block].instructions.end - 1 != index {
+            return None;
+        }
+        self.successors(block)
+            .find(|e| is_kind(e.kind))
+            .map(|e| self.blocks[e.to].instructions.start)
+    }
+}
+
+/// Offset of the jump target in the address space of the script instructions.
+/// MAIN uses absolute offsets, other scripts use negative offsets from their start
+pub(super) fn get_target(inst: &Instruction, script_type: ScriptType) -> Option<u32> {
+    to_target(inst.params.first()?.to_offset()?, script_type)
+}
+
+/// Same as get_target for an encoded label param
+pub(super) fn to_target(offset: i32, script_type: ScriptType) -> Option<u32> {
+    match script_type {
+        ScriptType::MAIN if offset >= 0 => Some(offset as u32),
+        ScriptType::MAIN => None,
+        _ if offset <= 0 => Some(offset.unsigned_abs()),
+        _ => None,
+    }
+}
+
+pub(super) fn get_flow(inst: &Instruction, script_type: ScriptType) -> Flow {
+    match inst.name.as_str() {
+        GOTO => Flow::Jump(get_target(inst, script_type)),
+        GOTO_IF_FALSE | GOTO_IF_TRUE => Flow::Branch(get_target(inst, script_type)),
+        GOSUB => Flow::Call(get_target(inst, script_type)),
+        RETURN => Flow::Return,
+        TERMINATE_THIS_SCRIPT | TERMINATE_THIS_CUSTOM_SCRIPT => Flow::Exit,
+        _ => Flow::Next,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{InstructionParam, ParamValue};
+    use std::fmt;
+
+    #[derive(Debug)]
+    struct Label(i32);
+
+    impl fmt::Display for Label {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "@{}", self.0)
+        }
+    }
+
+    impl InstructionParam for Label {
+        fn to_string(&self) -> Option<String> {
+            None
+        }
+        fn to_offset(&self) -> Option<i32> {
+            Some(self.0)
+        }
+        fn to_int(&self) -> Option<i32> {
+            None
+        }
+        fn to_global(&self) -> Option<u16> {
+            None
+        }
+        fn value(&self) -> ParamValue {
+            ParamValue::Offset(self.0)
+        }
+    }
+
+    /// Instructions of 10 bytes each, the target is the label param
+    fn script(commands: &[(&str, Option<i32>)]) -> Vec<Instruction> {
+        commands
+            .iter()
+            .enumerate()
+            .map(|(index, (name, target))| Instruction {
+                opcode: 0,
+                name: name.to_string(),
+                negated: false,
+                size: 10,
+                offset: index as u32 * 10,
+                params: target
+                    .iter()
+                    .map(|&t| Box::new(Label(t)) as Box<dyn InstructionParam>)
+                    .collect(),
+            })
+            .collect()
+    }
+
+    fn edges(cfg: &Cfg) -> Vec<(usize, usize, EdgeKind)> {
+        let mut edges: Vec<_> = cfg.edges.iter().map(|e| (e.from, e.to, e.kind)).collect();
+        edges.sort_by_key(|&(from, to, _)| (from, to));
+        edges
+    }
+
+    #[test]
+    fn splits_blocks_at_labels_and_jumps() {
+        let instructions = script(&[
+            ("WAIT", None),
+            ("WAIT", None),
+            ("GOTO_IF_FALSE", Some(40)),
+            ("WAIT", None),
+            ("WAIT", None),
+            ("GOTO", Some(10)),
+        ]);
+        let cfg = Cfg::new(&instructions, ScriptType::MAIN);
+
+        let blocks: Vec<_> = cfg.blocks.iter().map(|b| b.instructions.clone()).collect();
+        assert_eq!(blocks, vec![0..1, 1..3, 3..4, 4..6]);
+        assert_eq!(
+            edges(&cfg),
+            vec![
+                (0, 1, EdgeKind::FallThrough),
+                (1, 2, EdgeKind::FallThrough),
+                (1, 3, EdgeKind::Branch),
+                (2, 3, EdgeKind::FallThrough),
+                (3, 1, EdgeKind::Jump),
+            ]
+        );
+        assert!(cfg.exits.is_empty());
+        assert_eq!(cfg.block_of(5), Some(3));
+        assert_eq!(cfg.jump_target(2), Some(4));
+        assert_eq!(cfg.jump_target(5), Some(1));
+        assert_eq!(cfg.jump_target(4), None);
+    }
+
+    #[test]
+    fn links_gosub_and_return() {
+        let instructions = script(&[
+            ("GOSUB", Some(-40)),
+            ("GOSUB", Some(-40)),
+            ("TERMINATE_THIS_SCRIPT", None),
+            ("WAIT", None),
+            ("WAIT", None),
+            ("GOTO_IF_FALSE", Some(-70)),
+            ("RETURN", None),
+            ("RETURN", None),
+        ]);
+        let cfg = Cfg::new(&instructions, ScriptType::MISSION);
+
+        let blocks: Vec<_> = cfg.blocks.iter().map(|b| b.instructions.clone()).collect();
+        assert_eq!(blocks, vec![0..1, 1..2, 2..3, 3..4, 4..6, 6..7, 7..8]);
+        assert_eq!(
+            edges(&cfg),
+            vec![
+                (0, 1, EdgeKind::FallThrough),
+                (0, 4, EdgeKind::Call),
+                (1, 2, EdgeKind::FallThrough),
+                (1, 4, EdgeKind::Call),
+                (3, 4, EdgeKind::FallThrough),
+                (4, 5, EdgeKind::FallThrough),
+                (4, 6, EdgeKind::Branch),
+                (5, 1, EdgeKind::Return),
+                (5, 2, EdgeKind::Return),
+                (6, 1, EdgeKind::Return),
+                (6, 2, EdgeKind::Return),
+            ]
+        );
+        // both RETURNs go back to their callers, only TERMINATE_THIS_SCRIPT ends the script
+        assert_eq!(cfg.exits, vec![2]);
+        let predecessors: Vec<_> = cfg.predecessors(2).map(|e| (e.from, e.kind)).collect();
+        assert_eq!(
+            predecessors,
+            [
+                (1, EdgeKind::FallThrough),
+                (5, EdgeKind::Return),
+                (6, EdgeKind::Return)
+            ]
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use crate::library::Command;
+use crate::types::{Instruction, Opcode};
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+const IF: &str = "IF";
+const ANDOR: &str = "ANDOR";
+const GOTO_IF_FALSE: &str = "GOTO_IF_FALSE";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConditionKind {
+    And,
+    Or,
+}
+
+/// ANDOR with its conditions and the closing GOTO_IF_FALSE
+#[derive(Debug)]
+pub struct Condition {
+    pub kind: ConditionKind,
+    /// index of the ANDOR instruction in the IR
+    pub start: usize,
+    /// indices of the condition instructions
+    pub conditions: Range<usize>,
+    /// negation bit of each condition
+    pub negated: Vec<bool>,
+    /// index of the GOTO_IF_FALSE instruction
+    pub jump: usize,
+}
+
+impl Condition {
+    /// Single condition, no AND/OR keyword needed
+    pub fn is_single(&self) -> bool {
+        self.conditions.len() == 1
+    }
+}
+
+/// Decodes the ANDOR operand: 0 is a single condition,
+/// 1..=7 join 2 to 8 conditions with AND, 21..=27 with OR
+fn decode(operand: i32) -> Option<(ConditionKind, usize)> {
+    match operand {
+        0..=7 => Some((ConditionKind::And, operand as usize + 1)),
+        21..=27 => Some((ConditionKind::Or, operand as usize - 19)),
+        _ => None,
+    }
+}
+
+/// Groups every ANDOR followed by the encoded number of conditions and a GOTO_IF_FALSE.
+/// Blocks that do not follow this layout are left as plain instructions
+pub fn find_conditions(
+    instructions: &[Instruction],
+    definitions: &HashMap<Opcode, Command>,
+) -> Vec<Condition> {
+    let is_condition = |i: &Instruction| {
+        definitions
+            .get(&(i.opcode & 0x7FFF))
+            .is_some_and(|def| def.attrs.is_condition)
+    };
+
+    let mut result = vec![];
+    for (start, inst) in instructions.iter().enumerate() {
+        if inst.name != IF && inst.name != ANDOR {
+            continue;
+        }
+        let Some((kind, count)) = inst
+            .params
+            .first()
+            .and_then(|p| p.to_int())
+            .and_then(decode)
+        else {
+            continue;
+        };
+        let conditions = start + 1..start + 1 + count;
+        let jump = conditions.end;
+        let Some(closing) = instructions.get(jump) else {
+            continue;
+        };
+        if closing.name != GOTO_IF_FALSE
+            || !instructions[conditions.clone()].iter().all(is_condition)
+        {
+            continue;
+        }
+        result.push(Condition {
+            kind,
+            start,
+            negated: instructions[conditions.clone()]
+                .iter()
+                .map(|i| i.negated)
+                .collect(),
+            conditions,
+            jump,
+        });
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::test_support::disassemble;
+
+    #[test]
+    fn decodes_operand() {
+        assert_eq!(decode(0), Some((ConditionKind::And, 1)));
+        assert_eq!(decode(7), Some((ConditionKind::And, 8)));
+        assert_eq!(decode(21), Some((ConditionKind::Or, 2)));
+        assert_eq!(decode(27), Some((ConditionKind::Or, 8)));
+        for operand in [-1, 8, 20, 28] {
+            assert_eq!(decode(operand), None, "operand {}", operand);
+        }
+    }
+
+    #[test]
+    fn groups_conditions() {
+        let disassembly = disassemble(
+            "
+            IF 21
+            IS_INT_VAR_EQUAL_TO_NUMBER gvar_8 1
+            NOT IS_INT_VAR_EQUAL_TO_NUMBER gvar_8 2
+            GOTO_IF_FALSE 100
+            IF 1
+            IS_INT_VAR_EQUAL_TO_NUMBER gvar_8 3
+            GOTO_IF_FALSE 100
+            IF 8
+            IS_INT_VAR_EQUAL_TO_NUMBER gvar_8 4
+            GOTO_IF_FALSE 100
+            :100
+            IF 0
+            IS_INT_VAR_EQUAL_TO_NUMBER gvar_8 5
+            GOTO_IF_FALSE 100
+            ",
+            &[],
+            &Default::default(),
+        );
+        // the AND of two has a single condition, 8 is no operand
+        let conditions: Vec<_> = disassembly.scripts[0]
+            .conditions
+            .iter()
+            .map(|c| {
+                (
+                    c.kind,
+                    c.start,
+                    c.conditions.clone(),
+                    c.negated.clone(),
+                    c.jump,
+                )
+            })
+            .collect();
+        assert_eq!(
+            conditions,
+            [
+                (ConditionKind::Or, 0, 1..3, vec![false, true], 3),
+                (ConditionKind::And, 10, 11..12, vec![false], 12),
+            ]
+        );
+    }
+}
@@ -0,0 +1,755 @@
+//! CLEO Redux JavaScript export
+
+use super::cfg::{Cfg, EdgeKind};
+use super::condition::ConditionKind;
+use super::sanny::{get_label_name, get_target_label};
+use super::structure::{Node, Structure};
+use super::{to_ascii, to_hex, GlobalContext, IR};
+use crate::library::{Command, CommandParamType};
+use crate::types::{Instruction, Opcode, ParamValue, ScriptType, Variable, HEX_BLOCK};
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{self, Write};
+
+const INDENT: usize = 4;
+
+const DECLARATIONS_PER_LINE: usize = 8;
+
+const WAIT: &str = "WAIT";
+
+/// Commands ending the script
+const EXITS: &[&str] = &["TERMINATE_THIS_SCRIPT", "TERMINATE_THIS_CUSTOM_SCRIPT"];
+
+const GOTO: &str = "GOTO";
+const GOSUB: &str = "GOSUB";
+const RETURN: &str = "RETURN";
+
+/// Jumps that have no JavaScript counterpart, they throw when not structured
+const JUMPS: &[&str] = &["GOTO_IF_FALSE", "GOTO_IF_TRUE"];
+
+/// Condition headers left outside of a condition block, kept as comments
+const KEYWORDS: &[&str] = &["IF", "ANDOR"];
+
+/// Variable commands written as `a op b`, LVAR commands use the same operators as VAR ones
+const OPERATORS: &[(&str, &str)] = &[
+    ("SET_VAR_INT", "="),
+    ("SET_VAR_FLOAT", "="),
+    ("SET_VAR_INT_TO_VAR_INT", "="),
+    ("SET_VAR_FLOAT_TO_VAR_FLOAT", "="),
+    ("SET_VAR_INT_TO_CONSTANT", "="),
+    ("CSET_VAR_INT_TO_VAR_FLOAT", "="),
+    ("CSET_VAR_FLOAT_TO_VAR_INT", "="),
+    ("ADD_VAL_TO_INT_VAR", "+="),
+    ("ADD_VAL_TO_FLOAT_VAR", "+="),
+    ("ADD_INT_VAR_TO_INT_VAR", "+="),
+    ("ADD_FLOAT_VAR_TO_FLOAT_VAR", "+="),
+    ("SUB_VAL_FROM_INT_VAR", "-="),
+    ("SUB_VAL_FROM_FLOAT_VAR", "-="),
+    ("SUB_INT_VAR_FROM_INT_VAR", "-="),
+    ("SUB_FLOAT_VAR_FROM_FLOAT_VAR", "-="),
+    ("MULT_INT_VAR_BY_VAL", "*="),
+    ("MULT_FLOAT_VAR_BY_VAL", "*="),
+    ("MULT_INT_VAR_BY_INT_VAR", "*="),
+    ("MULT_FLOAT_VAR_BY_FLOAT_VAR", "*="),
+    ("DIV_INT_VAR_BY_VAL", "/="),
+    ("DIV_FLOAT_VAR_BY_VAL", "/="),
+    ("DIV_INT_VAR_BY_INT_VAR", "/="),
+    ("DIV_FLOAT_VAR_BY_FLOAT_VAR", "/="),
+    ("IS_INT_VAR_GREATER_THAN_NUMBER", ">"),
+    ("IS_NUMBER_GREATER_THAN_INT_VAR", ">"),
+    ("IS_INT_VAR_GREATER_THAN_INT_VAR", ">"),
+    ("IS_FLOAT_VAR_GREATER_THAN_NUMBER", ">"),
+    ("IS_NUMBER_GREATER_THAN_FLOAT_VAR", ">"),
+    ("IS_FLOAT_VAR_GREATER_THAN_FLOAT_VAR", ">"),
+    ("IS_INT_VAR_GREATER_OR_EQUAL_TO_NUMBER", ">="),
+    ("IS_NUMBER_GREATER_OR_EQUAL_TO_INT_VAR", ">="),
+    ("IS_INT_VAR_GREATER_OR_EQUAL_TO_INT_VAR", ">="),
+    ("IS_FLOAT_VAR_GREATER_OR_EQUAL_TO_NUMBER", ">="),
+    ("IS_NUMBER_GREATER_OR_EQUAL_TO_FLOAT_VAR", ">="),
+    ("IS_FLOAT_VAR_GREATER_OR_EQUAL_TO_FLOAT_VAR", ">="),
+    ("IS_INT_VAR_EQUAL_TO_NUMBER", "==="),
+    ("IS_INT_VAR_EQUAL_TO_INT_VAR", "==="),
+    ("IS_FLOAT_VAR_EQUAL_TO_NUMBER", "==="),
+    ("IS_FLOAT_VAR_EQUAL_TO_FLOAT_VAR", "==="),
+    ("IS_INT_VAR_NOT_EQUAL_TO_NUMBER", "!=="),
+    ("IS_INT_VAR_NOT_EQUAL_TO_INT_VAR", "!=="),
+    ("IS_FLOAT_VAR_NOT_EQUAL_TO_NUMBER", "!=="),
+    ("IS_FLOAT_VAR_NOT_EQUAL_TO_FLOAT_VAR", "!=="),
+];
+
+fn format_var(var: &Variable) -> String {
+    match var {
+        Variable::Global(offset) => format!("gvar_{}", offset),
+        Variable::Local(index) => format!("lvar_{}", index),
+    }
+}
+
+fn format_value(ir: &IR, value: &ParamValue) -> String {
+    match value {
+        ParamValue::EOL => String::new(),
+        ParamValue::Raw(d) => format!("0x{:02X}", d),
+        ParamValue::Int(d) => d.to_string(),
+        ParamValue::Float(d) => d.to_string(),
+        ParamValue::String(s) | ParamValue::LongString(s) => format!("{:?}", s),
+        ParamValue::Offset(d) => format!("\"@{}\"", get_target_label(ir, *d)),
+        ParamValue::Model { id, name } => format!("{} /* {} */", id, name),
+        ParamValue::Var(var, _) => format_var(var),
+        ParamValue::Array { var, index, .. } => {
+            format!("{}[{}]", format_var(var), format_var(index))
+        }
+    }
+}
+
+/// Instance members are lowerCamelCase in CLEO Redux, static ones keep the library name
+fn to_instance_member(member: &str) -> String {
+    let mut chars = member.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn format_call(def: &Command, class: &str, member: &str, args: &[String]) -> String {
+    let is_static = def.attrs.is_static || def.attrs.is_constructor;
+    match args.split_first() {
+        Some((this, rest)) if !is_static => {
+            format!(
+                "{}.{}({})",
+                this,
+                to_instance_member(member),
+                rest.join(", ")
+            )
+        }
+        _ => format!("{}.{}({})", class, member, args.join(", ")),
+    }
+}
+
+/// Keywords have an empty class and member in the library
+fn non_empty(name: &Option<String>) -> Option<&str> {
+    name.as_deref().filter(|s| !s.is_empty())
+}
+
+/// Instruction with its labels as in the text format, e.g. `goto @MAIN_123`
+fn format_jump(ir: &IR, inst: &Instruction) -> String {
+    let mut words = vec![inst.name.to_lowercase()];
+    words.extend(inst.params.iter().map(|p| match p.value() {
+        ParamValue::Offset(d) => format!("@{}", get_target_label(ir, d)),
+        value => format_value(ir, &value),
+    }));
+    words.join(" ")
+}
+
+/// Variables assigned an instance of a class (`gvar_8 = Player.Create(...)`), other variables
+/// holding a handle are wrapped in a new instance when a command is called on them
+fn collect_instances(ir: &IR, definitions: &HashMap<Opcode, Command>) -> HashSet<String> {
+    let classes: HashSet<&str> = definitions
+        .values()
+        .filter_map(|d| non_empty(&d.class))
+        .collect();
+    let mut instances = HashSet::new();
+    for inst in &ir.instructions {
+        let Some(def) = definitions.get(&(inst.opcode & 0x7FFF)) else {
+            continue;
+        };
+        let values: Vec<ParamValue> = inst
+            .params
+            .iter()
+            .map(|p| p.value())
+            .filter(|v| *v != ParamValue::EOL)
+            .collect();
+        if values.len() != def.input.len() + def.output.len() {
+            continue;
+        }
+        for (param, value) in def.output.iter().zip(&values[def.input.len()..]) {
+            let is_instance =
+                matches!(&param.r#type, CommandParamType::Any(t) if classes.contains(t.as_str()));
+            if is_instance && matches!(value, ParamValue::Var(..) | ParamValue::Array { .. }) {
+                instances.insert(format_value(ir, value));
+            }
+        }
+    }
+    instances
+}
+
+/// Command as a JavaScript expression, output params are assigned from the returned value.
+/// The negation bit is ignored
+fn format_command(
+    ir: &IR,
+    inst: &Instruction,
+    definitions: &HashMap<Opcode, Command>,
+    instances: &HashSet<String>,
+) -> String {
+    let values: Vec<String> = inst
+        .params
+        .iter()
+        .map(|p| p.value())
+        .filter(|v| *v != ParamValue::EOL)
+        .map(|v| format_value(ir, &v))
+        .collect();
+    let def = definitions.get(&(inst.opcode & 0x7FFF));
+    let normalized = inst.name.replace("LVAR", "VAR");
+
+    if let Some((_, op)) = OPERATORS.iter().find(|(n, _)| *n == normalized) {
+        format!(
+            "{} {} {}",
+            values[0],
+            op,
+            values.get(1).cloned().unwrap_or_default()
+        )
+    } else if inst.name == WAIT {
+        format!("wait({})", values.join(", "))
+    } else if EXITS.contains(&inst.name.as_str()) {
+        String::from("exit()")
+    } else {
+        match def.and_then(|d| Some((d, non_empty(&d.class)?, non_empty(&d.member)?))) {
+            Some((def, class, member)) if values.len() == def.input.len() + def.output.len() => {
+                let (inputs, outputs) = values.split_at(def.input.len());
+                let mut inputs = inputs.to_vec();
+                let is_static = def.attrs.is_static || def.attrs.is_constructor;
+                match inputs.first_mut() {
+                    Some(this) if !is_static && !instances.contains(this) => {
+                        *this = format!("new {}({})", class, this);
+                    }
+                    _ => {}
+                }
+                let call = format_call(def, class, member, &inputs);
+                match outputs {
+                    [] => call,
+                    [output] => format!("{} = {}", output, call),
+                    // multiple results are returned as an object with the param names as keys
+                    _ => {
+                        let fields: Vec<String> = def
+                            .output
+                            .iter()
+                            .zip(outputs)
+                            .enumerate()
+                            .map(|(n, (param, output))| match param.name.as_str() {
+                                "" => format!("{}: {}", n, output),
+                                name => format!("{}: {}", name, output),
+                            })
+                            .collect();
+                        format!("({{ {} }} = {})", fields.join(", "), call)
+                    }
+                }
+            }
+            _ => {
+                let mut args = vec![format!("{:?}", inst.name)];
+                args.extend(values);
+                format!("native({})", args.join(", "))
+            }
+        }
+    }
+}
+
+/// Command as a JavaScript expression, negated conditions are wrapped in `!()`
+fn format_expression(
+    ir: &IR,
+    inst: &Instruction,
+    definitions: &HashMap<Opcode, Command>,
+    instances: &HashSet<String>,
+) -> String {
+    let expression = format_command(ir, inst, definitions, instances);
+    if inst.negated {
+        format!("!({})", expression)
+    } else {
+        expression
+    }
+}
+
+/// Instruction indices of the loop the statements are written in
+#[derive(Clone, Copy)]
+struct Loop {
+    head: usize,
+    exit: usize,
+}
+
+struct JsWriter<'a> {
+    ir: &'a IR,
+    definitions: &'a HashMap<Opcode, Command>,
+    structure: Structure,
+    /// variables holding class instances, see `collect_instances`
+    instances: HashSet<String>,
+    /// number of jumps written as `throw`
+    unstructured: Cell<usize>,
+    /// instruction ranges of the subroutines written as functions, by their first index
+    subroutines: BTreeMap<usize, usize>,
+    /// indices of the threads with the header written, it goes before the outermost block
+    /// starting the thread
+    written: RefCell<HashSet<usize>>,
+}
+
+impl JsWriter<'_> {
+    fn write_thread<W: Write>(&self, index: usize, indent: usize, out: &mut W) -> io::Result<()> {
+        let thread = self.ir.symbols.get(self.ir.instructions[index].offset);
+        if let Some(thread) = thread.filter(|_| self.written.borrow_mut().insert(index)) {
+            if index > 0 {
+                writeln!(out)?;
+            }
+            writeln!(out, "{:indent$}// {}", "", thread)?;
+        }
+        Ok(())
+    }
+
+    fn write_label<W: Write>(&self, index: usize, indent: usize, out: &mut W) -> io::Result<()> {
+        let offset = self.ir.instructions[index].offset;
+        self.write_thread(index, indent, out)?;
+        if self.structure.labels.contains(&offset) {
+            writeln!(out, "{:indent$}// :{}", "", get_label_name(self.ir, offset))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the data block before the instruction as comments
+    fn write_data<W: Write>(&self, index: usize, indent: usize, out: &mut W) -> io::Result<()> {
+        let Some(block) = self.ir.data.get(&index) else {
+            return Ok(());
+        };
+        let is_label = |offset| self.structure.labels.contains(&offset);
+        for (offset, bytes) in block.rows(is_label) {
+            if is_label(offset) {
+                writeln!(out, "{:indent$}// :{}", "", get_label_name(self.ir, offset))?;
+            }
+            writeln!(
+                out,
+                "{:indent$}// {} {} end // {}",
+                "",
+                HEX_BLOCK,
+                to_hex(bytes),
+                to_ascii(bytes)
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write_instruction<W: Write>(
+        &self,
+        index: usize,
+        innermost: Option<Loop>,
+        indent: usize,
+        out: &mut W,
+    ) -> io::Result<()> {
+        let inst = &self.ir.instructions[index];
+        let name = inst.name.as_str();
+        if name == GOTO {
+            return self.write_jump(index, innermost, indent, out);
+        }
+        if name == GOSUB {
+            let target = self.structure.cfg.call_target(index);
+            return match target.filter(|t| self.subroutines.contains_key(t)) {
+                Some(target) => writeln!(out, "{:indent$}{}();", "", self.function_name(target)),
+                None => self.write_throw(index, indent, out),
+            };
+        }
+        if name == RETURN {
+            return match self.subroutines.range(..=index).next_back() {
+                Some((_, &end)) if index < end => writeln!(out, "{:indent$}return;", ""),
+                _ => self.write_throw(index, indent, out),
+            };
+        }
+        if JUMPS.contains(&name) {
+            return self.write_throw(index, indent, out);
+        }
+        if KEYWORDS.contains(&name) {
+            return writeln!(out, "{:indent$}// {}", "", format_jump(self.ir, inst));
+        }
+        writeln!(
+            out,
+            "{:indent$}{};",
+            "",
+            format_expression(self.ir, inst, self.definitions, &self.instances)
+        )
+    }
+
+    /// `break` or `continue` for a jump to the exit or the head of the innermost loop,
+    /// other jumps throw
+    fn write_jump<W: Write>(
+        &self,
+        index: usize,
+        innermost: Option<Loop>,
+        indent: usize,
+        out: &mut W,
+    ) -> io::Result<()> {
+        let target = self.structure.cfg.jump_target(index);
+        match innermost {
+            Some(l) if target == Some(l.exit) => writeln!(out, "{:indent$}break;", ""),
+            Some(l) if target == Some(l.head) => writeln!(out, "{:indent$}continue;", ""),
+            _ => self.write_throw(index, indent, out),
+        }
+    }
+
+    /// Jump that has no place in the structured script, the script stops when it runs
+    fn write_throw<W: Write>(&self, index: usize, indent: usize, out: &mut W) -> io::Result<()> {
+        self.unstructured.set(self.unstructured.get() + 1);
+        let message = format!(
+            "unstructured {}",
+            format_jump(self.ir, &self.ir.instructions[index])
+        );
+        writeln!(out, "{:indent$}throw new Error({:?});", "", message)
+    }
+
+    fn function_name(&self, index: usize) -> String {
+        get_label_name(self.ir, self.ir.instructions[index].offset)
+            .replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+    }
+
+    /// Writes the top level statements, subroutines become functions
+    fn write_script<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut nodes = &self.structure.nodes[..];
+        while let Some(first) = nodes.first() {
+            let start = first.start(self.ir);
+            let Some((start, &end)) = start.and_then(|s| Some((s, self.subroutines.get(&s)?)))
+            else {
+                self.write_nodes(&nodes[..1], None, 0, out)?;
+                nodes = &nodes[1..];
+                continue;
+            };
+            let len = nodes
+                .iter()
+                .position(|n| n.start(self.ir).is_some_and(|s| s >= end))
+                .unwrap_or(nodes.len());
+            writeln!(out)?;
+            writeln!(out, "function {}() {{", self.function_name(start))?;
+            self.write_nodes(&nodes[..len], None, INDENT, out)?;
+            writeln!(out, "}}")?;
+            nodes = &nodes[len..];
+        }
+        Ok(())
+    }
+
+    /// Conditions joined with && or ||
+    fn format_condition(&self, n: usize) -> String {
+        let condition = &self.ir.conditions[n];
+        let separator = match condition.kind {
+            ConditionKind::And => " && ",
+            ConditionKind::Or => " || ",
+        };
+        self.ir.instructions[condition.conditions.clone()]
+            .iter()
+            .map(|i| format_expression(self.ir, i, self.definitions, &self.instances))
+            .collect::<Vec<_>>()
+            .join(separator)
+    }
+
+    /// Negation of the conditions: each one negated, joined with || for AND and && for OR
+    fn format_negated_condition(&self, n: usize) -> String {
+        let condition = &self.ir.conditions[n];
+        let separator = match condition.kind {
+            ConditionKind::And => " || ",
+            ConditionKind::Or => " && ",
+        };
+        self.ir.instructions[condition.conditions.clone()]
+            .iter()
+            .map(|i| {
+                let command = format_command(self.ir, i, self.definitions, &self.instances);
+                if i.negated {
+                    command
+                } else {
+                    format!("!({})", command)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(separator)
+    }
+
+    fn write_nodes<W: Write>(
+        &self,
+        nodes: &[Node],
+        innermost: Option<Loop>,
+        indent: usize,
+        out: &mut W,
+    ) -> io::Result<()> {
+        for node in nodes {
+            match node {
+                Node::Instruction(index) => {
+                    self.write_data(*index, indent, out)?;
+                    self.write_label(*index, indent, out)?;
+                    self.write_instruction(*index, innermost, indent, out)?;
+                }
+                Node::Condition(n) => {
+                    let condition = &self.ir.conditions[*n];
+                    self.write_data(condition.start, indent, out)?;
+                    self.write_label(condition.start, indent, out)?;
+                    writeln!(
+                        out,
+                        "{:indent$}if ({}) {{",
+                        "",
+                        self.format_negated_condition(*n)
+                    )?;
+                    self.write_jump(condition.jump, innermost, indent + INDENT, out)?;
+                    writeln!(out, "{:indent$}}}", "")?;
+                }
+                Node::If {
+                    condition,
+                    then,
+                    otherwise,
+                } => {
+                    let start = self.ir.conditions[*condition].start;
+                    self.write_data(start, indent, out)?;
+                    self.write_label(start, indent, out)?;
+                    writeln!(
+                        out,
+                        "{:indent$}if ({}) {{",
+                        "",
+                        self.format_condition(*condition)
+                    )?;
+                    self.write_nodes(then, innermost, indent + INDENT, out)?;
+                    if !otherwise.is_empty() {
+                        writeln!(out, "{:indent$}}} else {{", "")?;
+                        self.write_nodes(otherwise, innermost, indent + INDENT, out)?;
+                    }
+                    writeln!(out, "{:indent$}}}", "")?;
+                }
+                Node::While {
+                    condition,
+                    body,
+                    head,
+                    exit,
+                } => {
+                    match condition {
+                        Some(n) => {
+                            let start = self.ir.conditions[*n].start;
+                            self.write_data(start, indent, out)?;
+                            self.write_label(start, indent, out)?;
+                            writeln!(
+                                out,
+                                "{:indent$}while ({}) {{",
+                                "",
+                                self.format_condition(*n)
+                            )?;
+                        }
+                        None => {
+                            if let Some(index) = body.first().and_then(|head| head.start(self.ir)) {
+                                self.write_thread(index, indent, out)?;
+                            }
+                            writeln!(out, "{:indent$}while (true) {{", "")?
+                        }
+                    }
+                    let innermost = Loop {
+                        head: *head,
+                        exit: *exit,
+                    };
+                    self.write_nodes(body, Some(innermost), indent + INDENT, out)?;
+                    writeln!(out, "{:indent$}}}", "")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// GOSUB targets that can be written as functions, with the end of their instruction range.
+/// A subroutine is the shortest run of top level statements from its start that ends before
+/// a block it doesn't reach and that no jump enters from outside. Jumps leaving it throw
+/// like any other unstructured jump
+fn find_subroutines(ir: &IR, structure: &Structure) -> BTreeMap<usize, usize> {
+    let cfg = &structure.cfg;
+    let top_level: HashSet<usize> = structure
+        .nodes
+        .iter()
+        .filter_map(|n| n.start(ir))
+        .chain([ir.instructions.len()])
+        .collect();
+    let entries: BTreeSet<usize> = cfg
+        .edges
+        .iter()
+        .filter(|e| e.kind == EdgeKind::Call)
+        .map(|e| e.to)
+        .collect();
+
+    let mut subroutines = BTreeMap::new();
+    for entry in entries {
+        let start = cfg.blocks[entry].instructions.start;
+        if !top_level.contains(&start) {
+            continue;
+        }
+        let reachable = reachable(cfg, entry);
+        // blocks are numbered in the order of their instructions, the run is closed when
+        // every block jumping into it is a part of it
+        let (mut lowest, mut highest) = (entry, entry);
+        for end in entry + 1..=cfg.blocks.len() {
+            for edge in cfg.predecessors(end - 1).filter(|e| is_local(e.kind)) {
+                lowest = lowest.min(edge.from);
+                highest = highest.max(edge.from);
+            }
+            let next = cfg
+                .blocks
+                .get(end)
+                .map_or(ir.instructions.len(), |b| b.instructions.start);
+            if reachable.contains(&end) || !top_level.contains(&next) {
+                continue;
+            }
+            if lowest >= entry && highest < end {
+                subroutines.insert(start, next);
+                break;
+            }
+        }
+    }
+    subroutines
+}
+
+/// Whether the edge stays in the same subroutine
+fn is_local(kind: EdgeKind) -> bool {
+    !matches!(kind, EdgeKind::Call | EdgeKind::Return)
+}
+
+/// Blocks reachable from the block without following calls and returns
+fn reachable(cfg: &Cfg, block: usize) -> HashSet<usize> {
+    let mut visited = HashSet::from([block]);
+    let mut pending = vec![block];
+    while let Some(block) = pending.pop() {
+        for edge in cfg.successors(block).filter(|e| is_local(e.kind)) {
+            if visited.insert(edge.to) {
+                pending.push(edge.to);
+            }
+        }
+    }
+    visited
+}
+
+/// Variables used by the script, globals first
+fn collect_variables(ir: &IR) -> (BTreeSet<u16>, BTreeSet<u16>) {
+    let mut globals = BTreeSet::new();
+    let mut locals = BTreeSet::new();
+    let mut add = |var: &Variable| match var {
+        Variable::Global(offset) => globals.insert(*offset),
+        Variable::Local(index) => locals.insert(*index),
+    };
+    for param in ir.instructions.iter().flat_map(|i| i.params.iter()) {
+        match param.value() {
+            ParamValue::Var(var, _) => {
+                add(&var);
+            }
+            ParamValue::Array { var, index, .. } => {
+                add(&var);
+                add(&index);
+            }
+            _ => {}
+        }
+    }
+    (globals, locals)
+}
+
+/// Globals are declared by the main script, other scripts list the ones they use
+fn write_global_comments<W: Write>(vars: &[Variable], out: &mut W) -> io::Result<()> {
+    if vars.is_empty() {
+        return Ok(());
+    }
+    writeln!(out, "// variables of the main script")?;
+    for chunk in vars.chunks(DECLARATIONS_PER_LINE) {
+        let names: Vec<String> = chunk.iter().map(format_var).collect();
+        writeln!(out, "/* global {} */", names.join(", "))?;
+    }
+    writeln!(out)
+}
+
+fn write_declarations<W: Write>(comment: &str, vars: &[Variable], out: &mut W) -> io::Result<()> {
+    if vars.is_empty() {
+        return Ok(());
+    }
+    writeln!(out, "// {}", comment)?;
+    for chunk in vars.chunks(DECLARATIONS_PER_LINE) {
+        let names: Vec<String> = chunk.iter().map(format_var).collect();
+        writeln!(out, "let {};", names.join(", "))?;
+    }
+    writeln!(out)
+}
+
+/// Writes the script as CLEO Redux JavaScript with the control flow structured into blocks.
+/// Jumps leaving a loop become `break` and `continue`, other jumps that can't be structured
+/// throw an error. Returns the number of those jumps
+pub fn write<W: Write>(
+    ir: &IR,
+    global_context: &GlobalContext,
+    definitions: &HashMap<Opcode, Command>,
+    out: &mut W,
+) -> io::Result<usize> {
+    let (globals, locals) = collect_variables(ir);
+    let locals: Vec<Variable> = locals.into_iter().map(Variable::Local).collect();
+    if let ScriptType::MAIN = ir.script_type {
+        // the main script declares the globals of all scripts
+        let globals: Vec<Variable> = global_context
+            .globals
+            .iter()
+            .copied()
+            .chain(globals)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(Variable::Global)
+            .collect();
+        write_declarations("variables of the main script", &globals, out)?;
+    } else {
+        let globals: Vec<Variable> = globals.into_iter().map(Variable::Global).collect();
+        write_global_comments(&globals, out)?;
+    }
+    write_declarations("local variables", &locals, out)?;
+
+    let structure = Structure::new(ir, global_context);
+    let subroutines = find_subroutines(ir, &structure);
+    let writer = JsWriter {
+        ir,
+        definitions,
+        structure,
+        instances: collect_instances(ir, definitions),
+        unstructured: Cell::new(0),
+        subroutines,
+        written: RefCell::new(HashSet::new()),
+    };
+    writer.write_script(out)?;
+    writer.write_data(ir.instructions.len(), 0, out)?;
+    Ok(writer.unstructured.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::test_support::disassemble;
+
+    #[test]
+    fn writes_operators_instances_and_subroutines() {
+        let disassembly = disassemble(
+            "
+            CREATE_CAR 90 1.5 -2.0 3.0 gvar_8
+            SET_CAR_HEADING gvar_8 90.0
+            ADD_VAL_TO_INT_VAR gvar_12 5
+            GOSUB 40
+            TERMINATE_THIS_SCRIPT
+            :40
+            WAIT 0
+            RETURN
+            ",
+            &["SET_CAR_HEADING gvar_8 0.0"],
+            &Default::default(),
+        );
+        let mut out = vec![];
+        for ir in &disassembly.scripts {
+            let unstructured =
+                write(ir, &disassembly.context, &disassembly.definitions, &mut out).unwrap();
+            assert_eq!(unstructured, 0);
+        }
+        // the mission did not create the car, its handle is wrapped in an instance
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "// variables of the main script\n\
+             let gvar_8, gvar_12;\n\
+             \n\
+             // Thread at 0\n\
+             gvar_8 = Car.Create(90, 1.5, -2, 3);\n\
+             gvar_8.setHeading(90);\n\
+             gvar_12 += 5;\n\
+             MAIN_40();\n\
+             exit();\n\
+             \n\
+             function MAIN_40() {\n\
+             \x20   // :MAIN_40\n\
+             \x20   wait(0);\n\
+             \x20   return;\n\
+             }\n\
+             // variables of the main script\n\
+             /* global gvar_8 */\n\
+             \n\
+             // Thread mission_0 at 0\n\
+             new Car(gvar_8).setHeading(0);\n"
+        );
+    }
+}
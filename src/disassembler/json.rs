@@ -0,0 +1,249 @@
+//! Machine-readable output of the disassembly
+
+use super::sanny::get_target_label;
+use super::{to_hex, IR};
+use crate::types::{
+    Instruction, InstructionParam, Opcode, ParamValue, ScriptType, VarType, Variable,
+};
+
+use serde::Serialize;
+use std::io::{self, Write};
+
+/// Globals are addressed by their byte offset, locals by their index
+#[derive(Serialize)]
+#[serde(tag = "scope", rename_all = "lowercase")]
+enum Var {
+    Global { offset: u16 },
+    Local { index: u16 },
+}
+
+impl From<Variable> for Var {
+    fn from(var: Variable) -> Self {
+        match var {
+            Variable::Global(offset) => Var::Global { offset },
+            Variable::Local(index) => Var::Local { index },
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Param {
+    Eol,
+    Raw {
+        value: u8,
+    },
+    Int {
+        value: i32,
+    },
+    Float {
+        value: f32,
+    },
+    String {
+        value: String,
+    },
+    LongString {
+        value: String,
+    },
+    Label {
+        value: i32,
+        label: String,
+    },
+    Model {
+        value: i32,
+        name: String,
+    },
+    Var {
+        var: Var,
+        var_type: VarType,
+    },
+    Array {
+        var: Var,
+        index: Var,
+        size: u8,
+        element_type: VarType,
+    },
+}
+
+#[derive(Serialize)]
+struct ParamEntry {
+    /// type tag the param was encoded with, e.g. NUM8
+    data_type: Option<&'static str>,
+    #[serde(flatten)]
+    param: Param,
+}
+
+#[derive(Serialize)]
+struct InstructionEntry {
+    opcode: Opcode,
+    name: String,
+    offset: u32,
+    size: u32,
+    negated: bool,
+    params: Vec<ParamEntry>,
+}
+
+#[derive(Serialize)]
+struct DataEntry {
+    offset: u32,
+    /// hex digits separated by spaces
+    bytes: String,
+}
+
+#[derive(Serialize)]
+struct ThreadEntry {
+    offset: u32,
+    name: Option<String>,
+    started_by: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Script {
+    name: String,
+    #[serde(rename = "type")]
+    script_type: ScriptType,
+    base_offset: u32,
+    mission_index: Option<usize>,
+    threads: Vec<ThreadEntry>,
+    instructions: Vec<InstructionEntry>,
+    /// bytes that could not be decoded as instructions
+    data: Vec<DataEntry>,
+}
+
+fn to_param(ir: &IR, param: &dyn InstructionParam) -> ParamEntry {
+    let param_value = match param.value() {
+        ParamValue::EOL => Param::Eol,
+        ParamValue::Raw(value) => Param::Raw { value },
+        ParamValue::Int(value) => Param::Int { value },
+        ParamValue::Float(value) => Param::Float { value },
+        ParamValue::String(value) => Param::String { value },
+        ParamValue::LongString(value) => Param::LongString { value },
+        ParamValue::Offset(value) => Param::Label {
+            value,
+            label: get_target_label(ir, value),
+        },
+        ParamValue::Model { id, name } => Param::Model { value: id, name },
+        ParamValue::Var(var, var_type) => Param::Var {
+            var: var.into(),
+            var_type,
+        },
+        ParamValue::Array {
+            var,
+            index,
+            size,
+            element_type,
+        } => Param::Array {
+            var: var.into(),
+            index: index.into(),
+            size,
+            element_type,
+        },
+    };
+    ParamEntry {
+        data_type: param.data_type(),
+        param: param_value,
+    }
+}
+
+fn to_entry(ir: &IR, inst: &Instruction) -> InstructionEntry {
+    InstructionEntry {
+        opcode: inst.opcode,
+        name: inst.name.clone(),
+        offset: inst.offset,
+        size: inst.size,
+        negated: inst.negated,
+        params: inst
+            .params
+            .iter()
+            .map(|p| to_param(ir, p.as_ref()))
+            .collect(),
+    }
+}
+
+/// Writes the script as a JSON document with typed instruction params
+pub fn write<W: Write>(ir: &IR, out: &mut W) -> io::Result<()> {
+    let script = Script {
+        name: ir.name.clone(),
+        script_type: ir.script_type,
+        base_offset: ir.base_offset,
+        mission_index: ir.mission_index,
+        threads: ir
+            .symbols
+            .threads()
+            .map(|t| ThreadEntry {
+                offset: t.offset,
+                name: t.name.clone(),
+                started_by: t.started_by.clone(),
+            })
+            .collect(),
+        instructions: ir.instructions.iter().map(|i| to_entry(ir, i)).collect(),
+        data: ir
+            .data
+            .values()
+            .map(|block| DataEntry {
+                offset: block.offset,
+                bytes: to_hex(&block.bytes),
+            })
+            .collect(),
+    };
+    serde_json::to_writer_pretty(&mut *out, &script)?;
+    writeln!(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::test_support::disassemble;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn writes_typed_params() {
+        let disassembly = disassemble(
+            "
+            :0
+            SET_VAR_INT gvar_8 300
+            SET_VAR_FLOAT lvar_2 1.5
+            GOTO 0
+            ",
+            &[],
+            &Default::default(),
+        );
+        let mut out = vec![];
+        write(&disassembly.scripts[0], &mut out).unwrap();
+        let script: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(script["type"], "main");
+        assert_eq!(
+            script["threads"],
+            json!([{ "offset": 0, "name": null, "started_by": null }])
+        );
+        // integers keep the size they were encoded with, globals are addressed by offset
+        assert_eq!(
+            script["instructions"][0],
+            json!({
+                "opcode": 4,
+                "name": "SET_VAR_INT",
+                "offset": 0,
+                "size": 8,
+                "negated": false,
+                "params": [
+                    {
+                        "data_type": "GVAR",
+                        "type": "var",
+                        "var": { "scope": "global", "offset": 8 },
+                        "var_type": "any"
+                    },
+                    { "data_type": "NUM16", "type": "int", "value": 300 }
+                ]
+            })
+        );
+        assert_eq!(
+            script["instructions"][1]["params"][0]["var"],
+            json!({ "scope": "local", "index": 2 })
+        );
+        assert_eq!(
+            script["instructions"][2]["params"][0],
+            json!({ "data_type": "NUM32", "type": "label", "value": 0, "label": "MAIN_0" })
+        );
+        assert_eq!(script["data"], json!([]));
+    }
+}
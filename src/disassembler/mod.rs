@@ -1,113 +1,412 @@
-pub mod scanner;
+mod cfg;
+pub mod condition;
+pub mod js;
+pub mod json;
+pub mod sanny;
+pub(crate) mod scanner;
+mod structure;
+pub mod symbols;
+#[cfg(test)]
+mod test_support;
+pub(crate) mod traversal;
 
-use crate::library::Command;
+use crate::error::Warning;
+use crate::library::{Command, CommandParamType};
+use crate::loader::Header;
+use crate::parser::Diagnostic;
 use crate::types;
 use crate::types::*;
 
-use std::collections::HashMap;
-use std::convert::TryInto;
-use std::io::prelude::*;
-use std::{collections, fs, path};
+use std::collections;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, Write};
+use std::ops::Range;
 
+use itertools::Itertools;
 use slugify::slugify;
 
+/// Bytes per row of the data block dump
+const DUMP_WIDTH: usize = 16;
+
 #[derive(Default)]
 pub struct GlobalContext {
     pub targets: Vec<i32>,
+    /// offsets in MAIN where other threads start, with the command starting them first
+    pub threads: BTreeMap<u32, String>,
+    /// mission indices with the command starting them first
+    pub missions: BTreeMap<usize, String>,
+    /// global variables used by any of the scripts
+    pub globals: BTreeSet<u16>,
+    /// opcodes of the commands sharing their name with another one
+    pub aliases: collections::HashSet<types::Opcode>,
 }
 
 struct LocalContext {
     targets: collections::HashSet<i32>,
 }
 
-fn get_out_file_name(script_name: &String) -> String {
-    let mut out_file = format!("out/{}.txt", script_name);
-    let mut count = 0;
-    loop {
-        if path::Path::new(&out_file).is_file() {
-            count += 1;
-            out_file = format!("out/{}_{}.txt", script_name, count);
-        } else {
-            return out_file;
-        }
-    }
-}
-
-pub struct Disassembler<'a> {
+pub(crate) struct Disassembler<'a> {
     definitions: &'a HashMap<types::Opcode, Command>,
     scanner: &'a scanner::Scanner,
+    header: Option<&'a Header>,
 }
 
 impl<'a> Disassembler<'a> {
     pub fn new(
         definitions: &'a HashMap<types::Opcode, Command>,
         scanner: &'a scanner::Scanner,
+        header: Option<&'a Header>,
     ) -> Self {
         Self {
             definitions,
             scanner,
+            header,
         }
     }
 
-    pub fn run(&self, instructions: Vec<Box<Instruction>>, script_type: ScriptType) -> IR {
-        let mut name = String::from("noname");
+    fn apply_header(
+        &self,
+        inst: &mut Instruction,
+        header: &Header,
+        script: &str,
+        warnings: &mut Vec<Warning>,
+    ) {
+        let Some(def) = self.definitions.get(&(inst.opcode & 0x7FFF)) else {
+            return;
+        };
+        let def_params = def.input.iter().chain(def.output.iter());
+        for (param, def_param) in inst.params.iter_mut().zip(def_params) {
+            if let Some(global) = param.to_global() {
+                if !header.globals.contains(global as u32) {
+                    warnings.push(Warning::GlobalOutsideSpace {
+                        script: String::from(script),
+                        offset: inst.offset,
+                        global,
+                        count: header.globals.count(),
+                    });
+                }
+            }
+            if def_param.r#type != CommandParamType::Model {
+                continue;
+            }
+            let Some(id) = param.to_int() else {
+                continue;
+            };
+            if let Some(name) = header.get_model_name(id) {
+                *param = Box::new(ModelParam {
+                    id,
+                    name: name.to_string(),
+                    data_type: param.data_type(),
+                });
+            }
+        }
+    }
 
-        let name_def = self
-            .definitions
-            .iter()
-            .find(|(_id, c)| c.name == "SCRIPT_NAME")
-            .expect(&format!("Can't find a command with name SCRIPT_NAME"));
+    pub fn run(
+        &self,
+        instructions: Vec<Instruction>,
+        mut diagnostics: Vec<Diagnostic>,
+        script_type: ScriptType,
+        base_offset: u32,
+        mission_index: Option<usize>,
+        global_context: &GlobalContext,
+    ) -> IR {
+        let (mut instructions, data) = split_data(instructions);
+        // the bytes after a failed one are mostly the remains of the same instruction,
+        // only the first failure of a run explains it
+        diagnostics.sort_by_key(|d| d.offset);
+        diagnostics.dedup_by_key(|d| d.offset);
+        let failed: collections::HashSet<u32> = diagnostics.iter().map(|d| d.offset).collect();
+        diagnostics.retain(|d| {
+            let in_data = data.values().any(|block| block.contains(d.offset));
+            in_data && !d.offset.checked_sub(1).is_some_and(|o| failed.contains(&o))
+        });
+        let symbols =
+            symbols::SymbolTable::new(&instructions, script_type, mission_index, global_context);
+        let name = slugify!(symbols.script_name().unwrap_or("noname"), separator = "_");
 
+        let mut warnings = vec![];
+        if let Some(header) = self.header {
+            for i in instructions.iter_mut() {
+                self.apply_header(i, header, &name, &mut warnings);
+            }
+        }
         for i in &instructions {
-            if i.opcode == *name_def.0 {
-                name = i.params.get(0).unwrap().to_string();
-                break;
+            if i.has_stray_negation() {
+                warnings.push(Warning::StrayNegation {
+                    script: name.clone(),
+                    offset: i.offset,
+                    name: i.name.clone(),
+                });
             }
         }
+
         let targets = self.scanner.collect_relative_addresses(&instructions);
+        // a data block inside of the IF block breaks it up
+        let conditions = condition::find_conditions(&instructions, self.definitions)
+            .into_iter()
+            .filter(|c| data.range(c.start + 1..=c.jump).next().is_none())
+            .collect();
 
         if let ScriptType::MAIN = script_type {
-            if targets.len() > 0 {
-                println!("Warning: Relative offsets found in the MAIN script");
+            if !targets.is_empty() {
+                warnings.push(Warning::RelativeOffsetsInMain);
             }
         }
 
         IR {
-            name: slugify!(name.as_str(), separator = "_"),
+            name,
+            warnings,
+            unreached: vec![],
             instructions,
+            data,
+            diagnostics,
+            conditions,
             script_type,
+            base_offset,
+            mission_index,
+            symbols,
             state: LocalContext { targets },
         }
     }
 }
 
+/// Run of bytes that could not be decoded as instructions
+#[derive(Debug)]
+pub struct DataBlock {
+    pub offset: u32,
+    pub bytes: Vec<u8>,
+}
+
+impl DataBlock {
+    pub fn contains(&self, offset: u32) -> bool {
+        (self.offset..self.offset + self.bytes.len() as u32).contains(&offset)
+    }
+
+    /// Splits the block into dump rows of 16 bytes. Every label inside of the block
+    /// starts a new row, so that the jumps into the data still have their target
+    pub fn rows(&self, is_label: impl Fn(u32) -> bool) -> Vec<(u32, &[u8])> {
+        let mut rows = vec![];
+        let mut start = 0;
+        for i in 1..=self.bytes.len() {
+            let offset = self.offset + i as u32;
+            if i == self.bytes.len() || i - start == DUMP_WIDTH || is_label(offset) {
+                rows.push((self.offset + start as u32, &self.bytes[start..i]));
+                start = i;
+            }
+        }
+        rows
+    }
+}
+
+/// Bytes as hex digits separated by spaces
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).join(" ")
+}
+
+/// Printable ASCII characters of the bytes, others are replaced with dots
+pub fn to_ascii(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| match b {
+            0x20..=0x7E => b as char,
+            _ => '.',
+        })
+        .collect()
+}
+
+/// Takes the invalid instructions out of the list, consecutive bytes are joined into a data block
+fn split_data(instructions: Vec<Instruction>) -> (Vec<Instruction>, BTreeMap<usize, DataBlock>) {
+    let mut code = vec![];
+    let mut data: BTreeMap<usize, DataBlock> = BTreeMap::new();
+    for inst in instructions {
+        if inst.name != INVALID_OPCODE {
+            code.push(inst);
+            continue;
+        }
+        let bytes = inst.params.iter().filter_map(|p| match p.value() {
+            ParamValue::Raw(byte) => Some(byte),
+            _ => None,
+        });
+        data.entry(code.len())
+            .or_insert_with(|| DataBlock {
+                offset: inst.offset,
+                bytes: vec![],
+            })
+            .bytes
+            .extend(bytes);
+    }
+    (code, data)
+}
+
 pub struct IR {
     pub name: String,
-    pub instructions: Vec<Box<Instruction>>,
-    script_type: ScriptType,
+    pub instructions: Vec<Instruction>,
+    /// data blocks by the index of the instruction that follows them,
+    /// the length of `instructions` for a block at the end of the script
+    pub data: BTreeMap<usize, DataBlock>,
+    /// why the data blocks could not be decoded, by offset
+    pub diagnostics: Vec<Diagnostic>,
+    /// IF blocks grouped from the instructions
+    pub conditions: Vec<condition::Condition>,
+    pub script_type: ScriptType,
+    /// added to the instruction positions, MAIN is addressed from the start of the file
+    /// and other scripts from their own start
+    pub base_offset: u32,
+    pub mission_index: Option<usize>,
+    /// names of the threads
+    pub symbols: symbols::SymbolTable,
+    /// problems found while disassembling, in the order of the instructions
+    pub warnings: Vec<Warning>,
+    /// byte ranges no code path leads to, found with the recursive traversal
+    pub unreached: Vec<Range<u32>>,
     state: LocalContext,
 }
 
 impl IR {
-    pub fn print(self, global_context: &GlobalContext) {
-        let mut f = fs::File::create(get_out_file_name(&self.name)).unwrap();
-        for inst in self.instructions.iter() {
-            let inst_offset: i32 = inst.offset.try_into().unwrap();
-
-            match self.script_type {
-                ScriptType::MAIN => {
-                    if global_context.targets.contains(&inst_offset) {
-                        writeln!(f, "\n:{}", inst.offset).unwrap()
-                    }
+    /// Whether some instruction jumps to the given offset. MAIN is addressed with absolute offsets
+    /// collected from all scripts, other scripts use their own (negative) relative offsets
+    pub fn is_label(&self, global_context: &GlobalContext, offset: u32) -> bool {
+        let offset = offset as i32;
+        match self.script_type {
+            ScriptType::MAIN => global_context.targets.contains(&offset),
+            _ => self.state.targets.contains(&(-offset)),
+        }
+    }
+
+    pub fn write<W: Write>(&self, global_context: &GlobalContext, out: &mut W) -> io::Result<()> {
+        for (index, inst) in self.instructions.iter().enumerate() {
+            self.write_data(global_context, index, out)?;
+            let thread = self.symbols.get(inst.offset);
+            if let Some(thread) = thread {
+                if index > 0 {
+                    writeln!(out)?;
                 }
-                _ => {
-                    if self.state.targets.contains(&(-inst_offset)) {
-                        writeln!(f, "\n:{}", inst.offset).unwrap()
-                    }
+                writeln!(out, "// {}", thread)?;
+            }
+            if self.is_label(global_context, inst.offset) {
+                // the label belongs to the thread header
+                if thread.is_none() {
+                    writeln!(out)?;
                 }
+                writeln!(out, ":{}", inst.offset)?;
+            }
+            if global_context.aliases.contains(&(inst.opcode & 0x7FFF)) {
+                writeln!(out, "{}", RawOpcode(inst))?;
+            } else {
+                writeln!(out, "{}", inst)?;
             }
+        }
+        self.write_data(global_context, self.instructions.len(), out)
+    }
 
-            writeln!(f, "{}", inst).unwrap();
+    /// Writes the data block before the instruction as `{offset} hex 00 01 .. // ascii` rows
+    fn write_data<W: Write>(
+        &self,
+        global_context: &GlobalContext,
+        index: usize,
+        out: &mut W,
+    ) -> io::Result<()> {
+        let Some(block) = self.data.get(&index) else {
+            return Ok(());
+        };
+        let is_label = |offset| self.is_label(global_context, offset);
+        for (offset, bytes) in block.rows(is_label) {
+            if is_label(offset) {
+                writeln!(out, "\n:{}", offset)?;
+            }
+            writeln!(
+                out,
+                "{{{:>06}}} {} {:<width$} // {}",
+                offset,
+                HEX_BLOCK,
+                to_hex(bytes),
+                to_ascii(bytes),
+                width = DUMP_WIDTH * 3 - 1
+            )?;
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::test_support::disassemble;
+
+    #[test]
+    fn groups_invalid_bytes() {
+        let disassembly = disassemble(
+            "
+            WAIT 0
+            GOTO 30
+            hex FF FF FF FF FF FF FF FF 43 52 55 53 54 00 FF FF FF FF FF
+            :30
+            hex FF FF FF FF
+            TERMINATE_THIS_SCRIPT
+            ",
+            &[],
+            &Default::default(),
+        );
+        let ir = &disassembly.scripts[0];
+        // one block before the instruction that follows it
+        assert_eq!(ir.data.len(), 1);
+        assert_eq!((ir.data[&2].offset, ir.data[&2].bytes.len()), (11, 23));
+
+        // rows of 16 bytes, the label starts a new one
+        let mut out = vec![];
+        ir.write(&disassembly.context, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "// Thread at 0\n\
+             {000000} WAIT 0\n\
+             {000004} GOTO 30\n\
+             {000011} hex FF FF FF FF FF FF FF FF 43 52 55 53 54 00 FF FF // ........CRUST...\n\
+             {000027} hex FF FF FF                                        // ...\n\
+             \n\
+             :30\n\
+             {000030} hex FF FF FF FF                                     // ....\n\
+             {000034} TERMINATE_THIS_SCRIPT \n"
+        );
+
+        let mut out = vec![];
+        sanny::write(ir, &disassembly.context, &sanny::Syntax::Opcodes, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "// Thread at 0\n\
+             0001: wait 0\n\
+             0002: goto @MAIN_30\n\
+             hex FF FF FF FF FF FF FF FF 43 52 55 53 54 00 FF FF end // ........CRUST...\n\
+             hex FF FF FF end // ...\n\
+             \n\
+             :MAIN_30\n\
+             hex FF FF FF FF end // ....\n\
+             004E: terminate_this_script\n"
+        );
+    }
+
+    #[test]
+    fn explains_data_blocks() {
+        let disassembly = disassemble(
+            "
+            WAIT 0
+            hex 01 00 00
+            TERMINATE_THIS_SCRIPT
+            hex FF 7F 4E
+            ",
+            &[],
+            &Default::default(),
+        );
+        let ir = &disassembly.scripts[0];
+        assert_eq!(ir.data.len(), 2);
+        // the bytes after the first failure of a block are not explained again
+        let diagnostics: Vec<String> = ir.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "4 opcode 0001 param 0 expected Int data type 00: Unexpected EOL parameter",
+                "9 opcode 7FFF: Unknown opcode",
+            ]
+        );
     }
 }
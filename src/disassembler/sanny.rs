@@ -0,0 +1,611 @@
+//! Sanny Builder source output
+
+use super::cfg::get_target;
+use super::condition::{Condition, ConditionKind};
+use super::structure::{Node, Structure};
+use super::symbols::Thread;
+use super::{to_ascii, to_hex, GlobalContext, IR};
+use crate::library::Command;
+use crate::loader::Header;
+use crate::types::{Instruction, Opcode, ParamValue, ScriptType, VarType, Variable, HEX_BLOCK};
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+
+const INDENT: usize = 4;
+
+/// How commands are written
+pub enum Syntax<'a> {
+    /// `00A5: create_car #BANSHEE 0.0 0.0 0.0 $car`
+    Opcodes,
+    /// `$car = Car.Create(#BANSHEE, 0.0, 0.0, 0.0)` for commands with a class in the library
+    Classes(&'a HashMap<Opcode, Command>),
+}
+
+/// Name of a label at the given offset of the script
+pub fn get_label_name(ir: &IR, offset: u32) -> String {
+    match ir.script_type {
+        ScriptType::MAIN => format!("MAIN_{}", offset),
+        _ => format!("{}_{}", ir.name.to_uppercase(), offset),
+    }
+}
+
+/// Name of the label a jump parameter points to. Missions and external scripts
+/// address their own code with negative offsets and MAIN with positive ones
+pub fn get_target_label(ir: &IR, offset: i32) -> String {
+    match ir.script_type {
+        ScriptType::MAIN => get_label_name(ir, offset as u32),
+        _ if offset > 0 => format!("MAIN_{}", offset),
+        _ => get_label_name(ir, offset.unsigned_abs()),
+    }
+}
+
+/// Label the header gives as the start of the mission
+fn get_mission_label(index: usize) -> String {
+    format!("MISSION_{}_START", index)
+}
+
+/// Quotes the text, the quotes, backslashes and non-printable bytes are escaped
+fn quote(s: &str, quote: char) -> String {
+    let mut quoted = String::from(quote);
+    for c in s.chars() {
+        match c {
+            _ if c == quote || c == '\\' => quoted.extend(['\\', c]),
+            ' '..='~' => quoted.push(c),
+            _ => {
+                for b in c.to_string().bytes() {
+                    quoted += &format!("\\x{:02X}", b);
+                }
+            }
+        }
+    }
+    quoted.push(quote);
+    quoted
+}
+
+fn format_float(value: f32) -> String {
+    // Sanny Builder reads numbers without a decimal point as integers
+    let s = value.to_string();
+    if s.contains('.') || !value.is_finite() {
+        s
+    } else {
+        s + ".0"
+    }
+}
+
+fn format_var(var: &Variable, var_type: &VarType) -> String {
+    let prefix = match var_type {
+        VarType::ShortString => "s",
+        VarType::LongString => "v",
+        _ => "",
+    };
+    match var {
+        Variable::Global(offset) if offset % 4 == 0 => format!("{}${}", prefix, offset / 4),
+        Variable::Global(offset) => format!("{}&{}", prefix, offset),
+        Variable::Local(index) => format!("{}@{}", index, prefix),
+    }
+}
+
+fn format_param(ir: &IR, value: &ParamValue) -> String {
+    match value {
+        ParamValue::EOL => String::new(),
+        ParamValue::Raw(d) => format!("{:02X}", d),
+        ParamValue::Int(d) => d.to_string(),
+        ParamValue::Float(d) => format_float(*d),
+        ParamValue::String(s) => quote(s, '\''),
+        ParamValue::LongString(s) => quote(s, '"'),
+        ParamValue::Model { name, .. } => format!("#{}", name),
+        ParamValue::Offset(d) => format!("@{}", get_target_label(ir, *d)),
+        ParamValue::Var(var, var_type) => format_var(var, var_type),
+        ParamValue::Array {
+            var,
+            index,
+            size,
+            element_type,
+        } => {
+            let suffix = match element_type {
+                VarType::Any => "",
+                VarType::Int => "i",
+                VarType::Float => "f",
+                VarType::ShortString => "s",
+                VarType::LongString => "v",
+            };
+            format!(
+                "{}({},{}{})",
+                format_var(var, &VarType::Any),
+                format_var(index, &VarType::Any),
+                size,
+                suffix
+            )
+        }
+    }
+}
+
+/// Class member call with the output params assigned on the left-hand side
+fn format_member(
+    ir: &IR,
+    inst: &Instruction,
+    definitions: &HashMap<Opcode, Command>,
+) -> Option<String> {
+    let def = definitions.get(&(inst.opcode & 0x7FFF))?;
+    let (class, member) = (def.class.as_ref()?, def.member.as_ref()?);
+    // variadic commands don't map onto the declared params
+    if inst.params.len() != def.input.len() + def.output.len() {
+        return None;
+    }
+
+    let values: Vec<String> = inst
+        .params
+        .iter()
+        .map(|p| format_param(ir, &p.value()))
+        .collect();
+    let (inputs, outputs) = values.split_at(def.input.len());
+    let call = format!("{}.{}({})", class, member, inputs.join(", "));
+    let prefix = if inst.negated { "not " } else { "" };
+    match outputs {
+        [] => Some(format!("{}{}", prefix, call)),
+        _ => Some(format!("{}{} = {}", prefix, outputs.join(", "), call)),
+    }
+}
+
+fn write_instruction<W: Write>(
+    ir: &IR,
+    inst: &Instruction,
+    indent: usize,
+    syntax: &Syntax,
+    out: &mut W,
+) -> io::Result<()> {
+    write!(out, "{:indent$}", "")?;
+    if let Syntax::Classes(definitions) = syntax {
+        if let Some(line) = format_member(ir, inst, definitions) {
+            return writeln!(out, "{}", line);
+        }
+    }
+    write!(out, "{:04X}: ", inst.opcode)?;
+    if inst.negated {
+        write!(out, "not ")?;
+    }
+    write!(out, "{}", inst.name.to_lowercase())?;
+    for param in &inst.params {
+        write!(out, " {}", format_param(ir, &param.value()))?;
+    }
+    writeln!(out)
+}
+
+/// Writes the data block before the instruction as `hex .. end` rows
+fn write_data<W: Write>(
+    ir: &IR,
+    index: usize,
+    indent: usize,
+    is_label: impl Fn(u32) -> bool,
+    out: &mut W,
+) -> io::Result<()> {
+    let Some(block) = ir.data.get(&index) else {
+        return Ok(());
+    };
+    for (offset, bytes) in block.rows(&is_label) {
+        if is_label(offset) {
+            writeln!(out, "\n:{}", get_label_name(ir, offset))?;
+        }
+        writeln!(
+            out,
+            "{:indent$}{} {} end // {}",
+            "",
+            HEX_BLOCK,
+            to_hex(bytes),
+            to_ascii(bytes)
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes `if`/`while` followed by `and`/`or` when the condition is a group
+fn write_condition_header<W: Write>(
+    condition: &Condition,
+    statement: &str,
+    indent: usize,
+    out: &mut W,
+) -> io::Result<()> {
+    match condition.kind {
+        _ if condition.is_single() => writeln!(out, "{:indent$}{}", "", statement),
+        ConditionKind::And => writeln!(out, "{:indent$}{} and", "", statement),
+        ConditionKind::Or => writeln!(out, "{:indent$}{} or", "", statement),
+    }
+}
+
+/// Writes the DEFINE block Sanny Builder compiles the header of main.scm from
+pub fn write_header<W: Write>(header: &Header, out: &mut W) -> io::Result<()> {
+    write_defines(&header.models, header.missions.offsets.len(), out)
+}
+
+fn write_defines<W: Write>(models: &[String], missions: usize, out: &mut W) -> io::Result<()> {
+    writeln!(out, "DEFINE OBJECTS {}", models.len())?;
+    for model in models {
+        match model.as_str() {
+            "" => writeln!(out, "DEFINE OBJECT (noname)")?,
+            _ => writeln!(out, "DEFINE OBJECT {}", model)?,
+        }
+    }
+    writeln!(out)?;
+    writeln!(out, "DEFINE MISSIONS {}", missions)?;
+    for index in 0..missions {
+        writeln!(
+            out,
+            "DEFINE MISSION {} AT @{}",
+            index,
+            get_mission_label(index)
+        )?;
+    }
+    writeln!(out)
+}
+
+/// Writes the includes of the mission files at the end of MAIN
+pub fn write_includes<W: Write>(files: &[&String], out: &mut W) -> io::Result<()> {
+    writeln!(out)?;
+    for file in files {
+        writeln!(out, "{{$INCLUDE {}}}", file)?;
+    }
+    Ok(())
+}
+
+/// Writes the CLEO directive or the label the header starts the mission at
+fn write_script_header<W: Write>(ir: &IR, out: &mut W) -> io::Result<()> {
+    if let ScriptType::CLEO = ir.script_type {
+        writeln!(out, "{{$CLEO}}")?;
+    }
+    if let Some(index) = ir.mission_index {
+        writeln!(out, ":{}", get_mission_label(index))?;
+    }
+    Ok(())
+}
+
+/// Writes the script with IF blocks as `if and ... then ... end`. A condition jumping forward
+/// inside the enclosing block gets its body up to the jump target, other conditions keep the jump
+pub fn write<W: Write>(
+    ir: &IR,
+    global_context: &GlobalContext,
+    syntax: &Syntax,
+    out: &mut W,
+) -> io::Result<()> {
+    write_script_header(ir, out)?;
+    let instructions = &ir.instructions;
+    let index_of: HashMap<u32, usize> = instructions
+        .iter()
+        .enumerate()
+        .map(|(index, i)| (i.offset, index))
+        .collect();
+    let condition_at: HashMap<usize, &Condition> =
+        ir.conditions.iter().map(|c| (c.start, c)).collect();
+    let is_label = |index: usize| ir.is_label(global_context, instructions[index].offset);
+    let is_data_label = |offset| ir.is_label(global_context, offset);
+
+    // end indices of the open blocks, innermost last
+    let mut blocks: Vec<usize> = vec![];
+    let mut index = 0;
+    while index < instructions.len() {
+        // the bytes before the end of a block belong to it
+        write_data(ir, index, blocks.len() * INDENT, is_data_label, out)?;
+        while blocks.last() == Some(&index) {
+            blocks.pop();
+            writeln!(out, "{:indent$}end", "", indent = blocks.len() * INDENT)?;
+        }
+        let thread = ir.symbols.get(instructions[index].offset);
+        write_thread_label(ir, thread, index, is_label(index), out)?;
+        let indent = blocks.len() * INDENT;
+
+        let condition = condition_at
+            .get(&index)
+            .filter(|c| !(c.start + 1..=c.jump).any(is_label));
+        let Some(condition) = condition else {
+            write_instruction(ir, &instructions[index], indent, syntax, out)?;
+            index += 1;
+            continue;
+        };
+
+        write_condition_header(condition, "if", indent, out)?;
+        for inst in &instructions[condition.conditions.clone()] {
+            write_instruction(ir, inst, indent + INDENT, syntax, out)?;
+        }
+        let jump = &instructions[condition.jump];
+        let end = get_target(jump, ir.script_type)
+            .and_then(|target| index_of.get(&target))
+            .copied()
+            .filter(|&end| end > condition.jump && blocks.last().is_none_or(|&outer| end <= outer));
+        match end {
+            Some(end) => {
+                writeln!(out, "{:indent$}then", "")?;
+                blocks.push(end);
+            }
+            None => write_instruction(ir, jump, indent, syntax, out)?,
+        }
+        index = condition.jump + 1;
+    }
+    // blocks don't end past the last instruction
+    write_data(ir, index, 0, is_data_label, out)
+}
+
+/// Writes the header of the thread starting at the instruction and the label of the instruction
+fn write_thread_label<W: Write>(
+    ir: &IR,
+    thread: Option<&Thread>,
+    index: usize,
+    is_label: bool,
+    out: &mut W,
+) -> io::Result<()> {
+    if let Some(thread) = thread {
+        if index > 0 {
+            writeln!(out)?;
+        }
+        writeln!(out, "// {}", thread)?;
+    }
+    if is_label {
+        // the label belongs to the thread header
+        if thread.is_none() {
+            writeln!(out)?;
+        }
+        writeln!(
+            out,
+            ":{}",
+            get_label_name(ir, ir.instructions[index].offset)
+        )?;
+    }
+    Ok(())
+}
+
+fn write_label<W: Write>(
+    ir: &IR,
+    structure: &Structure,
+    written: &mut HashSet<usize>,
+    index: usize,
+    out: &mut W,
+) -> io::Result<()> {
+    let is_label = structure.labels.contains(&ir.instructions[index].offset);
+    write_thread_label(ir, take_thread(ir, written, index), index, is_label, out)
+}
+
+/// Thread starting at the instruction unless its header is already written.
+/// The header goes before the outermost block starting the thread
+fn take_thread<'a>(ir: &'a IR, written: &mut HashSet<usize>, index: usize) -> Option<&'a Thread> {
+    let thread = ir.symbols.get(ir.instructions[index].offset)?;
+    written.insert(index).then_some(thread)
+}
+
+/// Writes the data block before the instruction with the labels kept by the structure
+fn write_structured_data<W: Write>(
+    ir: &IR,
+    structure: &Structure,
+    index: usize,
+    indent: usize,
+    out: &mut W,
+) -> io::Result<()> {
+    let is_label = |offset| structure.labels.contains(&offset);
+    write_data(ir, index, indent, is_label, out)
+}
+
+fn write_nodes<W: Write>(
+    ir: &IR,
+    structure: &Structure,
+    written: &mut HashSet<usize>,
+    nodes: &[Node],
+    indent: usize,
+    syntax: &Syntax,
+    out: &mut W,
+) -> io::Result<()> {
+    for node in nodes {
+        match node {
+            Node::Instruction(index) => {
+                write_structured_data(ir, structure, *index, indent, out)?;
+                write_label(ir, structure, written, *index, out)?;
+                write_instruction(ir, &ir.instructions[*index], indent, syntax, out)?;
+            }
+            Node::Condition(n) => {
+                let condition = &ir.conditions[*n];
+                write_structured_data(ir, structure, condition.start, indent, out)?;
+                write_label(ir, structure, written, condition.start, out)?;
+                write_condition_header(condition, "if", indent, out)?;
+                for inst in &ir.instructions[condition.conditions.clone()] {
+                    write_instruction(ir, inst, indent + INDENT, syntax, out)?;
+                }
+                write_instruction(ir, &ir.instructions[condition.jump], indent, syntax, out)?;
+            }
+            Node::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let condition = &ir.conditions[*condition];
+                write_structured_data(ir, structure, condition.start, indent, out)?;
+                write_label(ir, structure, written, condition.start, out)?;
+                write_condition_header(condition, "if", indent, out)?;
+                for inst in &ir.instructions[condition.conditions.clone()] {
+                    write_instruction(ir, inst, indent + INDENT, syntax, out)?;
+                }
+                writeln!(out, "{:indent$}then", "")?;
+                write_nodes(ir, structure, written, then, indent + INDENT, syntax, out)?;
+                if !otherwise.is_empty() {
+                    writeln!(out, "{:indent$}else", "")?;
+                    write_nodes(
+                        ir,
+                        structure,
+                        written,
+                        otherwise,
+                        indent + INDENT,
+                        syntax,
+                        out,
+                    )?;
+                }
+                writeln!(out, "{:indent$}end", "")?;
+            }
+            Node::While {
+                condition, body, ..
+            } => {
+                match condition {
+                    Some(n) => {
+                        let condition = &ir.conditions[*n];
+                        write_structured_data(ir, structure, condition.start, indent, out)?;
+                        write_label(ir, structure, written, condition.start, out)?;
+                        if condition.is_single() {
+                            write!(out, "{:indent$}while ", "")?;
+                            write_instruction(
+                                ir,
+                                &ir.instructions[condition.conditions.start],
+                                0,
+                                syntax,
+                                out,
+                            )?;
+                        } else {
+                            // the conditions are indented past the body to tell them apart
+                            write_condition_header(condition, "while", indent, out)?;
+                            for inst in &ir.instructions[condition.conditions.clone()] {
+                                write_instruction(ir, inst, indent + 2 * INDENT, syntax, out)?;
+                            }
+                        }
+                    }
+                    // the loop head is the first instruction of the body
+                    None => {
+                        if let Some(index) = body.first().and_then(|head| head.start(ir)) {
+                            let thread = take_thread(ir, written, index);
+                            write_thread_label(ir, thread, index, false, out)?;
+                        }
+                        writeln!(out, "{:indent$}while true", "")?
+                    }
+                }
+                write_nodes(ir, structure, written, body, indent + INDENT, syntax, out)?;
+                writeln!(out, "{:indent$}end", "")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the script with the control flow structured into if/else/while blocks
+pub fn write_decompiled<W: Write>(
+    ir: &IR,
+    global_context: &GlobalContext,
+    syntax: &Syntax,
+    out: &mut W,
+) -> io::Result<()> {
+    write_script_header(ir, out)?;
+    let structure = Structure::new(ir, global_context);
+    let mut written = HashSet::new();
+    write_nodes(
+        ir,
+        &structure,
+        &mut written,
+        &structure.nodes,
+        0,
+        syntax,
+        out,
+    )?;
+    write_structured_data(ir, &structure, ir.instructions.len(), 0, out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::test_support::disassemble;
+
+    fn write_main(main: &str, missions: &[&str]) -> String {
+        let disassembly = disassemble(main, missions, &Default::default());
+        let mut out = vec![];
+        for ir in &disassembly.scripts {
+            write(ir, &disassembly.context, &Syntax::Opcodes, &mut out).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn writes_instructions() {
+        let source = write_main(
+            r#"
+            SCRIPT_NAME "it's\\"
+            :10
+            WAIT 250
+            GOTO 10
+            "#,
+            &[],
+        );
+        assert_eq!(
+            source,
+            "// Thread it's\\ at 0\n\
+             03A4: script_name 'it\\'s\\\\'\n\
+             \n\
+             :MAIN_10\n\
+             0001: wait 250\n\
+             0002: goto @MAIN_10\n"
+        );
+    }
+
+    #[test]
+    fn writes_class_members() {
+        let disassembly = disassemble(
+            "
+            :0
+            CREATE_CAR 90 1.5 -2.0 3.0 gvar_8
+            IF 0
+            NOT IS_PLAYER_PLAYING gvar_12
+            GOTO_IF_FALSE 0
+            WAIT 0
+            ",
+            &[],
+            &Default::default(),
+        );
+        let mut out = vec![];
+        let syntax = Syntax::Classes(&disassembly.definitions);
+        write(
+            &disassembly.scripts[0],
+            &disassembly.context,
+            &syntax,
+            &mut out,
+        )
+        .unwrap();
+        // commands without a class keep the opcode
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "// Thread at 0\n\
+             :MAIN_0\n\
+             $2 = Car.Create(90, 1.5, -2.0, 3.0)\n\
+             if\n\
+             \x20   not Player.IsPlaying($3)\n\
+             004D: goto_if_false @MAIN_0\n\
+             0001: wait 0\n"
+        );
+    }
+
+    #[test]
+    fn writes_header_and_mission_labels() {
+        let mut out = vec![];
+        write_defines(&[String::new(), String::from("CSPLAY")], 1, &mut out).unwrap();
+        write_includes(&[&String::from("mission_00_intro.txt")], &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "DEFINE OBJECTS 2\n\
+             DEFINE OBJECT (noname)\n\
+             DEFINE OBJECT CSPLAY\n\
+             \n\
+             DEFINE MISSIONS 1\n\
+             DEFINE MISSION 0 AT @MISSION_0_START\n\
+             \n\
+             \n\
+             {$INCLUDE mission_00_intro.txt}\n"
+        );
+
+        // the mission starts at the label of the header and jumps with its own labels
+        let source = write_main(
+            "TERMINATE_THIS_SCRIPT",
+            &["SCRIPT_NAME \"intro\"\n:5\nWAIT 0\nGOTO -5"],
+        );
+        assert_eq!(
+            source,
+            "// Thread at 0\n\
+             004E: terminate_this_script\n\
+             :MISSION_0_START\n\
+             // Thread intro at 0\n\
+             03A4: script_name 'intro'\n\
+             \n\
+             :INTRO_10\n\
+             0001: wait 0\n\
+             0002: goto @INTRO_10\n"
+        );
+    }
+}
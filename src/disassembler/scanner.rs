@@ -4,6 +4,10 @@ use crate::{
 };
 use std::collections::{self, HashMap};
 
+const START_NEW_SCRIPT: &str = "START_NEW_SCRIPT";
+const LAUNCH_MISSION: &str = "LAUNCH_MISSION";
+const LOAD_AND_LAUNCH_MISSION: &str = "LOAD_AND_LAUNCH_MISSION_INTERNAL";
+
 pub struct Scanner {
     branch_ops: Vec<types::Opcode>,
 }
@@ -12,19 +16,14 @@ impl<'a> Scanner {
     pub fn new(definitions: &'a HashMap<types::Opcode, Command>) -> Self {
         let branch_ops = definitions
             .iter()
-            .filter(|(_id, c)| {
-                c.input
-                    .iter()
-                    .find(|i| i.r#type == CommandParamType::Label)
-                    .is_some()
-            })
-            .map(|(id, _)| id.clone())
+            .filter(|(_id, c)| c.input.iter().any(|i| i.r#type == CommandParamType::Label))
+            .map(|(id, _)| *id)
             .collect::<Vec<_>>();
         Self { branch_ops }
     }
     pub fn collect_global_addresses(
         &self,
-        instructions: &Vec<Box<types::Instruction>>,
+        instructions: &[types::Instruction],
     ) -> collections::HashSet<i32> {
         let mut res: collections::HashSet<i32> = collections::HashSet::new();
 
@@ -41,9 +40,51 @@ impl<'a> Scanner {
         res
     }
 
+    /// Offsets in MAIN where START_NEW_SCRIPT and LAUNCH_MISSION start a thread,
+    /// with the name of the command
+    pub fn collect_threads(&self, instructions: &[types::Instruction]) -> Vec<(u32, String)> {
+        instructions
+            .iter()
+            .filter(|i| i.name == START_NEW_SCRIPT || i.name == LAUNCH_MISSION)
+            .filter_map(|i| match i.params.first()?.to_offset() {
+                Some(x) if x >= 0 => Some((x as u32, i.name.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Mission indices started with LOAD_AND_LAUNCH_MISSION, with the name of the command
+    pub fn collect_missions(&self, instructions: &[types::Instruction]) -> Vec<(usize, String)> {
+        instructions
+            .iter()
+            .filter(|i| i.name == LOAD_AND_LAUNCH_MISSION)
+            .filter_map(|i| match i.params.first()?.to_int() {
+                Some(x) if x >= 0 => Some((x as usize, i.name.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Global variables read or written by the instructions, array indices included
+    pub fn collect_globals(&self, instructions: &[types::Instruction]) -> Vec<u16> {
+        instructions
+            .iter()
+            .flat_map(|i| i.params.iter())
+            .flat_map(|p| match p.value() {
+                types::ParamValue::Var(var, _) => vec![var],
+                types::ParamValue::Array { var, index, .. } => vec![var, index],
+                _ => vec![],
+            })
+            .filter_map(|var| match var {
+                types::Variable::Global(offset) => Some(offset),
+                types::Variable::Local(_) => None,
+            })
+            .collect()
+    }
+
     pub fn collect_relative_addresses(
         &self,
-        instructions: &Vec<Box<types::Instruction>>,
+        instructions: &[types::Instruction],
     ) -> collections::HashSet<i32> {
         let mut res: collections::HashSet<i32> = collections::HashSet::new();
 
@@ -60,3 +101,62 @@ impl<'a> Scanner {
         res
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::disassembler::test_support::disassemble;
+
+    #[test]
+    fn finds_threads() {
+        let disassembly = disassemble(
+            "
+            START_NEW_SCRIPT 30
+            LAUNCH_MISSION 32
+            LOAD_AND_LAUNCH_MISSION_INTERNAL 1
+            :19
+            WAIT 0
+            GOTO 19
+            :30
+            TERMINATE_THIS_SCRIPT
+            :32
+            TERMINATE_THIS_SCRIPT
+            ",
+            &["TERMINATE_THIS_SCRIPT", "TERMINATE_THIS_SCRIPT"],
+            &Default::default(),
+        );
+        let context = &disassembly.context;
+        // the jump target is not a thread
+        let threads: Vec<(u32, &str)> = context
+            .threads
+            .iter()
+            .map(|(offset, command)| (*offset, command.as_str()))
+            .collect();
+        assert_eq!(threads, [(30, "START_NEW_SCRIPT"), (32, "LAUNCH_MISSION")]);
+        let missions: Vec<(usize, &str)> = context
+            .missions
+            .iter()
+            .map(|(index, command)| (*index, command.as_str()))
+            .collect();
+        assert_eq!(missions, [(1, "LOAD_AND_LAUNCH_MISSION_INTERNAL")]);
+
+        let mut out = vec![];
+        for ir in &disassembly.scripts {
+            ir.write(context, &mut out).unwrap();
+        }
+        let headers: Vec<&str> = std::str::from_utf8(&out)
+            .unwrap()
+            .lines()
+            .filter(|line| line.starts_with("// Thread"))
+            .collect();
+        assert_eq!(
+            headers,
+            [
+                "// Thread at 0",
+                "// Thread at 30, started with START_NEW_SCRIPT",
+                "// Thread at 32, started with LAUNCH_MISSION",
+                "// Thread mission_0 at 0",
+                "// Thread mission_1 at 0, started with LOAD_AND_LAUNCH_MISSION_INTERNAL 1",
+            ]
+        );
+    }
+}
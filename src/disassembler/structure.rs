@@ -0,0 +1,388 @@
+use super::cfg::{get_target, Cfg, EdgeKind};
+use super::condition::Condition;
+use super::{GlobalContext, IR};
+use crate::types::ScriptType;
+
+use std::collections::{HashMap, HashSet};
+
+const GOTO: &str = "GOTO";
+
+/// Statement of the structured script, instructions are referenced by their index in the IR
+#[derive(Debug)]
+pub enum Node {
+    Instruction(usize),
+    /// condition that keeps its GOTO_IF_FALSE, index in `IR::conditions`
+    Condition(usize),
+    If {
+        condition: usize,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    /// loop checking a condition (single or AND/OR group) at the head, or an endless loop without condition
+    While {
+        condition: Option<usize>,
+        body: Vec<Node>,
+        /// index of the first instruction of the loop, `continue` goes back to it
+        head: usize,
+        /// index of the instruction after the loop, `break` goes to it
+        exit: usize,
+    },
+}
+
+impl Node {
+    /// Index of the first instruction of the statement, none for an empty endless loop
+    pub fn start(&self, ir: &IR) -> Option<usize> {
+        match self {
+            Node::Instruction(index) => Some(*index),
+            Node::Condition(n) | Node::If { condition: n, .. } => Some(ir.conditions[*n].start),
+            Node::While {
+                condition: Some(n), ..
+            } => Some(ir.conditions[*n].start),
+            Node::While {
+                condition: None,
+                body,
+                ..
+            } => body.first()?.start(ir),
+        }
+    }
+}
+
+pub struct Structure {
+    pub nodes: Vec<Node>,
+    /// offsets of the labels that are still jumped to after structuring
+    pub labels: HashSet<u32>,
+    pub cfg: Cfg,
+}
+
+struct Builder<'a> {
+    ir: &'a IR,
+    cfg: Cfg,
+    index_of: HashMap<u32, usize>,
+    /// index in `IR::conditions` by the index of the ANDOR instruction
+    condition_at: HashMap<usize, usize>,
+    /// indices of the instructions found as branch targets by the scanner
+    targets: HashSet<usize>,
+    /// indices of the GOTO instructions jumping back to the given index (the back edges of the graph), ascending
+    back_jumps: HashMap<usize, Vec<usize>>,
+    /// jumps replaced by block boundaries
+    consumed: HashSet<usize>,
+}
+
+impl Structure {
+    /// Turns conditional jumps into if/else/while blocks. A block is only formed when
+    /// all of its jumps stay within the enclosing block, other jumps are kept with their labels
+    pub fn new(ir: &IR, global_context: &GlobalContext) -> Self {
+        let instructions = &ir.instructions;
+        let index_of: HashMap<u32, usize> = instructions
+            .iter()
+            .enumerate()
+            .map(|(index, i)| (i.offset, index))
+            .collect();
+
+        let mut builder = Builder {
+            ir,
+            cfg: Cfg::new(instructions, ir.script_type),
+            condition_at: ir
+                .conditions
+                .iter()
+                .enumerate()
+                .map(|(n, c)| (c.start, n))
+                .collect(),
+            targets: (0..instructions.len())
+                .filter(|&index| ir.is_label(global_context, instructions[index].offset))
+                .collect(),
+            back_jumps: HashMap::new(),
+            consumed: HashSet::new(),
+            index_of,
+        };
+        for edge in builder
+            .cfg
+            .edges
+            .iter()
+            .filter(|e| e.kind == EdgeKind::Jump)
+        {
+            let index = builder.cfg.blocks[edge.from].instructions.end - 1;
+            let target = builder.cfg.blocks[edge.to].instructions.start;
+            // the data before the loop head would end up in the loop body
+            if target <= index && builder.is_goto(index) && !builder.has_data(target) {
+                builder.back_jumps.entry(target).or_default().push(index)
+            }
+        }
+        for jumps in builder.back_jumps.values_mut() {
+            jumps.sort_unstable();
+        }
+
+        let nodes = builder.build(0, instructions.len());
+        let labels = builder.get_labels(global_context);
+        Self {
+            nodes,
+            labels,
+            cfg: builder.cfg,
+        }
+    }
+}
+
+impl Builder<'_> {
+    fn target_of(&self, index: usize) -> Option<usize> {
+        self.cfg.jump_target(index)
+    }
+
+    /// Instruction referenced by the label param of the instruction at the index
+    fn label_of(&self, index: usize) -> Option<usize> {
+        let target = get_target(&self.ir.instructions[index], self.ir.script_type)?;
+        self.index_of.get(&target).copied()
+    }
+
+    /// Whether a data block precedes the instruction
+    fn has_data(&self, index: usize) -> bool {
+        self.ir.data.contains_key(&index)
+    }
+
+    /// Jump that can become a block boundary, a data block before it would not be written
+    fn is_goto(&self, index: usize) -> bool {
+        self.ir.instructions[index].name == GOTO && !self.has_data(index)
+    }
+
+    fn is_goto_to(&self, index: usize, target: usize) -> bool {
+        self.is_goto(index) && self.target_of(index) == Some(target)
+    }
+
+    /// Condition starting at the index, unless something jumps into its middle
+    fn condition(&self, index: usize) -> Option<(usize, &'_ Condition)> {
+        let &n = self.condition_at.get(&index)?;
+        let condition = &self.ir.conditions[n];
+        let jumped_into = (condition.start + 1..=condition.jump).any(|i| self.targets.contains(&i));
+        (!jumped_into).then_some((n, condition))
+    }
+
+    fn build(&mut self, start: usize, end: usize) -> Vec<Node> {
+        let mut nodes = vec![];
+        let mut index = start;
+        while index < end {
+            if let Some((node, next)) = self
+                .match_while(index, end)
+                .or_else(|| self.match_if(index, end))
+            {
+                nodes.push(node);
+                index = next;
+            } else if let Some((n, condition)) = self.condition(index) {
+                index = condition.jump + 1;
+                nodes.push(Node::Condition(n));
+            } else {
+                nodes.push(Node::Instruction(index));
+                index += 1;
+            }
+        }
+        nodes
+    }
+
+    /// `:head if cond jf @exit ... goto @head :exit` or `:head ... goto @head`
+    fn match_while(&mut self, head: usize, end: usize) -> Option<(Node, usize)> {
+        if !self.targets.contains(&head) {
+            return None;
+        }
+
+        if let Some((n, condition)) = self.condition(head) {
+            let jump = condition.jump;
+            let exit = self
+                .target_of(jump)
+                .filter(|&exit| exit > jump + 1 && exit <= end && self.is_goto_to(exit - 1, head));
+            if let Some(exit) = exit {
+                self.consumed.extend([jump, exit - 1]);
+                let body = self.build(jump + 1, exit - 1);
+                return Some((
+                    Node::While {
+                        condition: Some(n),
+                        body,
+                        head,
+                        exit,
+                    },
+                    exit,
+                ));
+            }
+        }
+
+        let last = self
+            .back_jumps
+            .get(&head)?
+            .iter()
+            .rev()
+            .find(|&&index| index < end)
+            .copied()?;
+        self.consumed.insert(last);
+        let body = self.build(head, last);
+        Some((
+            Node::While {
+                condition: None,
+                body,
+                head,
+                exit: last + 1,
+            },
+            last + 1,
+        ))
+    }
+
+    /// `if cond jf @else ... goto @end :else ... :end` or `if cond jf @end ... :end`
+    fn match_if(&mut self, index: usize, end: usize) -> Option<(Node, usize)> {
+        let (n, condition) = self.condition(index)?;
+        let jump = condition.jump;
+        let target = self
+            .target_of(jump)
+            .filter(|&target| target > jump && target <= end)?;
+        self.consumed.insert(jump);
+
+        let goto = target - 1;
+        let exit = if goto > jump && self.is_goto(goto) {
+            self.target_of(goto)
+                .filter(|&exit| exit > target && exit <= end)
+        } else {
+            None
+        };
+        let node = match exit {
+            Some(exit) => {
+                self.consumed.insert(goto);
+                Node::If {
+                    condition: n,
+                    then: self.build(jump + 1, goto),
+                    otherwise: self.build(target, exit),
+                }
+            }
+            None => Node::If {
+                condition: n,
+                then: self.build(jump + 1, target),
+                otherwise: vec![],
+            },
+        };
+        Some((node, exit.unwrap_or(target)))
+    }
+
+    /// Labels referenced by the jumps that were not replaced by blocks.
+    /// Labels of MAIN may also be referenced by other scripts
+    fn get_labels(&self, global_context: &GlobalContext) -> HashSet<u32> {
+        let instructions = &self.ir.instructions;
+        let referenced: HashSet<usize> = (0..instructions.len())
+            .filter(|index| !self.consumed.contains(index))
+            .filter_map(|index| self.label_of(index))
+            .collect();
+
+        let mut external = HashSet::new();
+        if let ScriptType::MAIN = self.ir.script_type {
+            // every script adds a target once, more than one entry means some other script uses it
+            let mut counts: HashMap<i32, usize> = HashMap::new();
+            for &target in &global_context.targets {
+                *counts.entry(target).or_default() += 1;
+            }
+            let own: HashSet<usize> = (0..instructions.len())
+                .filter_map(|index| self.label_of(index))
+                .collect();
+            for &index in &self.targets {
+                let count = counts
+                    .get(&(instructions[index].offset as i32))
+                    .copied()
+                    .unwrap_or(0);
+                if count > own.contains(&index) as usize {
+                    external.insert(index);
+                }
+            }
+        }
+
+        self.targets
+            .iter()
+            .filter(|index| referenced.contains(index) || external.contains(index))
+            .map(|&index| instructions[index].offset)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::test_support::disassemble;
+
+    /// Nodes as `while 0 { if 1 { 4 } else { 6 } }`, instructions by their index
+    fn shape(nodes: &[Node]) -> String {
+        let block = |nodes: &[Node]| format!("{{ {} }}", shape(nodes));
+        nodes
+            .iter()
+            .map(|node| match node {
+                Node::Instruction(index) => index.to_string(),
+                Node::Condition(n) => format!("cond {}", n),
+                Node::If {
+                    condition,
+                    then,
+                    otherwise,
+                } if otherwise.is_empty() => format!("if {} {}", condition, block(then)),
+                Node::If {
+                    condition,
+                    then,
+                    otherwise,
+                } => format!("if {} {} else {}", condition, block(then), block(otherwise)),
+                Node::While {
+                    condition: Some(n),
+                    body,
+                    ..
+                } => format!("while {} {}", n, block(body)),
+                Node::While {
+                    condition: None,
+                    body,
+                    ..
+                } => format!("loop {}", block(body)),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn folds_if_else_and_while() {
+        let disassembly = disassemble(
+            "
+            :0
+            IF 0
+            IS_INT_VAR_EQUAL_TO_NUMBER gvar_8 0
+            GOTO_IF_FALSE 200
+            IF 0
+            IS_INT_VAR_EQUAL_TO_NUMBER gvar_8 1
+            GOTO_IF_FALSE 100
+            WAIT 1
+            GOTO 150
+            :100
+            WAIT 2
+            :150
+            IF 0
+            IS_INT_VAR_EQUAL_TO_NUMBER gvar_8 2
+            GOTO_IF_FALSE 170
+            GOTO 200
+            :170
+            IF 0
+            IS_INT_VAR_EQUAL_TO_NUMBER gvar_8 3
+            GOTO_IF_FALSE 180
+            GOTO 0
+            :180
+            WAIT 3
+            GOTO 0
+            :200
+            TERMINATE_THIS_SCRIPT
+            ",
+            &[],
+            &Default::default(),
+        );
+        let ir = &disassembly.scripts[0];
+        let structure = Structure::new(ir, &disassembly.context);
+        assert_eq!(
+            shape(&structure.nodes),
+            "while 0 { if 1 { 6 } else { 8 } if 2 { 12 } if 3 { 16 } 17 } 19"
+        );
+
+        // the jumps out of the loop and back to its head are kept for break and continue
+        let Node::While { head, exit, .. } = structure.nodes[0] else {
+            panic!("no loop");
+        };
+        assert_eq!((head, exit), (0, 19));
+        assert_eq!(structure.cfg.jump_target(12), Some(exit));
+        assert_eq!(structure.cfg.jump_target(16), Some(head));
+        let offset = |index: usize| ir.instructions[index].offset;
+        assert!(structure.labels.contains(&offset(head)));
+        assert!(structure.labels.contains(&offset(exit)));
+        assert!(!structure.labels.contains(&offset(8)));
+    }
+}
@@ -0,0 +1,174 @@
+//! Names of the threads of a script
+
+use super::GlobalContext;
+use crate::types::{Instruction, InstructionParam, ScriptType};
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+const SCRIPT_NAME: &str = "SCRIPT_NAME";
+
+/// Part of a script that runs as its own thread
+pub struct Thread {
+    pub offset: u32,
+    /// text of the SCRIPT_NAME run by the thread, missions without one are named by their index
+    pub name: Option<String>,
+    /// command starting the thread, none for MAIN itself
+    pub started_by: Option<String>,
+}
+
+impl fmt::Display for Thread {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Thread")?;
+        if let Some(name) = &self.name {
+            write!(f, " {}", name)?;
+        }
+        write!(f, " at {}", self.offset)?;
+        if let Some(command) = &self.started_by {
+            write!(f, ", started with {}", command)?;
+        }
+        Ok(())
+    }
+}
+
+/// Threads of a script by the offset of their first instruction: the script itself
+/// and the code of MAIN started with START_NEW_SCRIPT or LAUNCH_MISSION
+#[derive(Default)]
+pub struct SymbolTable {
+    threads: BTreeMap<u32, Thread>,
+}
+
+impl SymbolTable {
+    /// Each SCRIPT_NAME names the thread it belongs to, the first one wins
+    /// when a thread has several of them
+    pub fn new(
+        instructions: &[Instruction],
+        script_type: ScriptType,
+        mission_index: Option<usize>,
+        global_context: &GlobalContext,
+    ) -> Self {
+        let mut threads = BTreeMap::new();
+        for (index, inst) in instructions.iter().enumerate() {
+            let started_by = match (index, mission_index, script_type) {
+                (0, Some(mission), _) => global_context
+                    .missions
+                    .get(&mission)
+                    .map(|command| format!("{} {}", command, mission)),
+                (0, _, _) => None,
+                (_, _, ScriptType::MAIN) => match global_context.threads.get(&inst.offset) {
+                    Some(command) => Some(command.clone()),
+                    None => continue,
+                },
+                _ => break,
+            };
+            threads.insert(
+                inst.offset,
+                Thread {
+                    offset: inst.offset,
+                    name: None,
+                    started_by,
+                },
+            );
+        }
+
+        let mut symbols = Self { threads };
+        for inst in instructions.iter().filter(|i| i.name == SCRIPT_NAME) {
+            let name = inst
+                .params
+                .first()
+                .and_then(|p| InstructionParam::to_string(p.as_ref()));
+            if let Some(thread) = symbols.find_mut(inst.offset) {
+                if thread.name.is_none() {
+                    thread.name = name;
+                }
+            }
+        }
+        if let (Some(index), Some(thread)) = (mission_index, symbols.threads.values_mut().next()) {
+            thread
+                .name
+                .get_or_insert_with(|| format!("mission_{}", index));
+        }
+        symbols
+    }
+
+    /// Thread starting at the offset
+    pub fn get(&self, offset: u32) -> Option<&Thread> {
+        self.threads.get(&offset)
+    }
+
+    /// Thread the instruction at the offset belongs to
+    pub fn find(&self, offset: u32) -> Option<&Thread> {
+        self.threads.range(..=offset).next_back().map(|(_, t)| t)
+    }
+
+    fn find_mut(&mut self, offset: u32) -> Option<&mut Thread> {
+        self.threads
+            .range_mut(..=offset)
+            .next_back()
+            .map(|(_, t)| t)
+    }
+
+    /// Name of the script: the name of its first thread
+    pub fn script_name(&self) -> Option<&str> {
+        self.threads.values().next()?.name.as_deref()
+    }
+
+    pub fn threads(&self) -> impl Iterator<Item = &Thread> {
+        self.threads.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disassembler::test_support::disassemble;
+
+    #[test]
+    fn names_threads() {
+        let disassembly = disassemble(
+            "
+            SCRIPT_NAME \"main\"
+            START_NEW_SCRIPT 28
+            START_NEW_SCRIPT 54
+            TERMINATE_THIS_SCRIPT
+            :28
+            WAIT 0
+            SCRIPT_NAME \"first\"
+            SCRIPT_NAME \"second\"
+            TERMINATE_THIS_SCRIPT
+            :54
+            TERMINATE_THIS_SCRIPT
+            ",
+            &["SCRIPT_NAME \"intro\"", "TERMINATE_THIS_SCRIPT"],
+            &Default::default(),
+        );
+        let names: Vec<Vec<(u32, Option<&str>)>> = disassembly
+            .scripts
+            .iter()
+            .map(|ir| {
+                ir.symbols
+                    .threads()
+                    .map(|t| (t.offset, t.name.as_deref()))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            names,
+            [
+                vec![(0, Some("main")), (28, Some("first")), (54, None)],
+                vec![(0, Some("intro"))],
+                vec![(0, Some("mission_1"))],
+            ]
+        );
+
+        // the instructions belong to the nearest thread before them
+        let main = &disassembly.scripts[0];
+        assert_eq!(main.symbols.find(52).map(|t| t.offset), Some(28));
+        // scripts are named after their first thread
+        let names: Vec<&str> = disassembly
+            .scripts
+            .iter()
+            .map(|ir| ir.name.as_str())
+            .collect();
+        assert_eq!(names, ["main", "intro", "mission_1"]);
+    }
+}
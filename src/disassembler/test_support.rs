@@ -0,0 +1,51 @@
+//! Scripts for the writer tests, written in the text format and assembled for GTA III
+
+use crate::assembler::Assembler;
+use crate::disassembly::{disassemble_loaded, Disassembly, Options};
+use crate::library::{Command, Library};
+use crate::loader::{Loaded, Script};
+use crate::platform::Game;
+use crate::types::{Opcode, ScriptType};
+
+use std::collections::HashMap;
+
+/// Commands of GTA III from the library of the repository
+pub fn definitions() -> HashMap<Opcode, Command> {
+    Library::new(concat!(env!("CARGO_MANIFEST_DIR"), "/gta3.json"))
+        .unwrap()
+        .to_map()
+}
+
+/// Disassembles the scripts written in the text format, MAIN first and the missions after it.
+/// Labels are numbers like the offsets of the text output, missions refer to their own
+/// labels with negative numbers
+pub fn disassemble(main: &str, missions: &[&str], options: &Options) -> Disassembly {
+    let definitions = definitions();
+    let assembler = Assembler::new(&Game::GTA3, &definitions).unwrap();
+    let (chunk, main_labels) = assembler
+        .assemble_source("main", main, 0, &[], None)
+        .unwrap();
+    let mut scripts = vec![Script {
+        chunk,
+        script_type: ScriptType::MAIN,
+        base_offset: 0,
+        mission_index: None,
+    }];
+    for (index, mission) in missions.iter().enumerate() {
+        let (chunk, _) = assembler
+            .assemble_source("mission", mission, 0, &[], Some(&main_labels))
+            .unwrap();
+        scripts.push(Script {
+            chunk,
+            script_type: ScriptType::MISSION,
+            base_offset: 0,
+            mission_index: Some(index),
+        });
+    }
+    let loaded = Loaded {
+        header: None,
+        scripts,
+        warnings: vec![],
+    };
+    disassemble_loaded(definitions, &Game::GTA3, loaded, options)
+}
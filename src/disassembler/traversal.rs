@@ -0,0 +1,139 @@
+use super::cfg::{get_flow, to_target};
+use crate::parser::{Diagnostic, Parse};
+use crate::types::{Instruction, ScriptType};
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// Result of the recursive traversal of a script
+pub struct Traversal {
+    /// reachable instructions followed by the unreachable bytes as invalid instructions, by offset
+    pub instructions: Vec<Instruction>,
+    /// offsets of the byte ranges no code path leads to
+    pub data: Vec<Range<u32>>,
+    /// errors of the instructions that failed to decode
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Decodes the code reachable from the start of the script and the given entry points
+/// (offsets in the address space of the script instructions). Every jump, GOSUB and
+/// label param (START_NEW_SCRIPT, LAUNCH_MISSION, ...) is followed
+pub fn traverse(parser: &mut dyn Parse, script_type: ScriptType, entries: &[u32]) -> Traversal {
+    let (size, base_offset) = {
+        let p = parser.get_parser();
+        (p.size, p.base_offset)
+    };
+    let to_position = |offset: u32| offset.checked_sub(base_offset).filter(|&p| p < size);
+
+    // decoded instructions by position
+    let mut decoded: BTreeMap<u32, Instruction> = BTreeMap::new();
+    let mut pending: Vec<u32> = vec![];
+    if size > 0 {
+        pending.push(0);
+    }
+    pending.extend(entries.iter().filter_map(|&e| to_position(e)));
+
+    while let Some(mut position) = pending.pop() {
+        while position < size {
+            if decoded.contains_key(&position) || overlaps(&decoded, position) {
+                break;
+            }
+            let Ok(inst) = parser.decode(position) else {
+                break;
+            };
+            let next = position + inst.size;
+            // the instruction would run into code decoded before
+            if decoded.range(position + 1..next).next().is_some() {
+                break;
+            }
+
+            for param in &inst.params {
+                let target = param
+                    .to_offset()
+                    .and_then(|offset| to_target(offset, script_type))
+                    .and_then(to_position);
+                if let Some(target) = target {
+                    pending.push(target);
+                }
+            }
+            let falls_through = get_flow(&inst, script_type).falls_through();
+            decoded.insert(position, inst);
+            if !falls_through {
+                break;
+            }
+            position = next;
+        }
+    }
+
+    let mut instructions = vec![];
+    let mut data = vec![];
+    let mut position = 0;
+    while position < size {
+        if let Some(inst) = decoded.remove(&position) {
+            position += inst.size;
+            instructions.push(inst);
+            continue;
+        }
+        let end = decoded.keys().next().copied().unwrap_or(size);
+        data.push(position + base_offset..end + base_offset);
+        for p in position..end {
+            if let Ok(inst) = parser.decode_raw(p) {
+                instructions.push(inst);
+            }
+        }
+        position = end;
+    }
+
+    Traversal {
+        instructions,
+        data,
+        diagnostics: std::mem::take(&mut parser.get_parser_as_mut().diagnostics),
+    }
+}
+
+/// Whether the position is inside of a decoded instruction
+fn overlaps(decoded: &BTreeMap<u32, Instruction>, position: u32) -> bool {
+    decoded
+        .range(..position)
+        .next_back()
+        .is_some_and(|(start, inst)| start + inst.size > position)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disassembler::test_support::disassemble;
+    use crate::disassembly::Options;
+
+    /// The bytes jumped over are a valid WAIT 0
+    const SCRIPT: &str = "
+        WAIT 0
+        GOTO 15
+        hex 01 00 04 00
+        :15
+        TERMINATE_THIS_SCRIPT
+        ";
+
+    #[test]
+    fn skips_unreachable_bytes() {
+        let options = Options {
+            recursive: true,
+            ..Default::default()
+        };
+        let ir = &disassemble(SCRIPT, &[], &options).scripts[0];
+        let offsets: Vec<u32> = ir.instructions.iter().map(|i| i.offset).collect();
+        assert_eq!(offsets, [0, 4, 15]);
+        assert_eq!(ir.unreached.len(), 1);
+        assert_eq!(ir.unreached[0], 11..15);
+        let block = &ir.data[&2];
+        assert_eq!(
+            (block.offset, &block.bytes[..]),
+            (11, &[0x01, 0x00, 0x04, 0x00][..])
+        );
+
+        // decoding every byte in order finds the dead instruction
+        let ir = &disassemble(SCRIPT, &[], &Options::default()).scripts[0];
+        let offsets: Vec<u32> = ir.instructions.iter().map(|i| i.offset).collect();
+        assert_eq!(offsets, [0, 4, 11, 15]);
+        assert!(ir.data.is_empty() && ir.unreached.is_empty());
+    }
+}
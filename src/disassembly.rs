@@ -0,0 +1,203 @@
+use crate::disassembler::{scanner::Scanner, traversal, Disassembler, GlobalContext, IR};
+use crate::error::{Error, Warning};
+use crate::jobs::run_jobs;
+use crate::library::{Command, Library};
+use crate::loader::{self, Header, Loaded, Script, ScriptKind};
+use crate::platform::{self, Game};
+use crate::types::{Opcode, ScriptType};
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Script file to disassemble
+pub struct Input<'a> {
+    pub path: &'a Path,
+    /// archive with the streamed scripts (script.img)
+    pub img: Option<&'a Path>,
+    pub kind: ScriptKind,
+    pub game: Game,
+}
+
+pub struct Options {
+    /// decode only the code reachable from the entry points, the rest is kept as data
+    pub recursive: bool,
+    /// number of worker threads, 1 disassembles in the calling thread
+    pub jobs: u32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            recursive: false,
+            jobs: 1,
+        }
+    }
+}
+
+/// Disassembled scripts of a file with everything needed to write them out
+pub struct Disassembly {
+    pub header: Option<Header>,
+    /// scripts in the loading order: MAIN, missions, streamed scripts
+    pub scripts: Vec<IR>,
+    /// jump targets, threads and global variables of all scripts
+    pub context: GlobalContext,
+    /// command definitions the scripts were decoded with
+    pub definitions: HashMap<Opcode, Command>,
+    /// problems found in the header, the scripts keep their own
+    pub warnings: Vec<Warning>,
+}
+
+/// Loads the file and disassembles all of its scripts. CLEO scripts prefer the commands
+/// of the CLEO extension of the library
+pub fn disassemble(
+    library: &Library,
+    input: &Input,
+    options: &Options,
+) -> Result<Disassembly, Error> {
+    let definitions = match input.kind {
+        ScriptKind::Cleo => library.to_map_preferring("CLEO"),
+        ScriptKind::Scm => library.to_map(),
+    };
+    let loaded = loader::load(input.path, input.img, input.kind, &input.game, &definitions)?;
+    Ok(disassemble_loaded(
+        definitions,
+        &input.game,
+        loaded,
+        options,
+    ))
+}
+
+/// Disassembles the scripts of a loaded file with the definitions they were loaded with
+pub(crate) fn disassemble_loaded(
+    definitions: HashMap<Opcode, Command>,
+    game: &Game,
+    loaded: Loaded,
+    options: &Options,
+) -> Disassembly {
+    let scripts = loaded.scripts;
+    let scanner = Scanner::new(&definitions);
+    let dasm = Disassembler::new(&definitions, &scanner, loaded.header.as_ref());
+
+    let decode = |scr: &Script, entries: &[u32]| {
+        let mut parser = platform::get_parser(game, &scr.chunk, &definitions, scr.base_offset);
+        if options.recursive {
+            traversal::traverse(parser.as_mut(), scr.script_type, entries)
+        } else {
+            traversal::Traversal {
+                instructions: parser.by_ref().collect(),
+                data: vec![],
+                diagnostics: std::mem::take(&mut parser.get_parser_as_mut().diagnostics),
+            }
+        }
+    };
+    let mut decoded = run_jobs(options.jobs, scripts.iter().collect(), |scr| {
+        decode(scr, &[])
+    });
+
+    if options.recursive {
+        // code of MAIN that only other scripts jump to
+        let entries: Vec<u32> = scripts
+            .iter()
+            .zip(&decoded)
+            .filter(|(scr, _)| !matches!(scr.script_type, ScriptType::MAIN))
+            .flat_map(|(_, t)| scanner.collect_global_addresses(&t.instructions))
+            .map(|offset| offset as u32)
+            .collect();
+        for (scr, t) in scripts.iter().zip(decoded.iter_mut()) {
+            let in_data = |e: &u32| t.data.iter().any(|range| range.contains(e));
+            if matches!(scr.script_type, ScriptType::MAIN) && entries.iter().any(in_data) {
+                *t = decode(scr, &entries);
+            }
+        }
+    }
+
+    // jump targets, threads and global variables of all scripts, in the loading order
+    let mut context = GlobalContext {
+        aliases: get_aliases(&definitions),
+        ..Default::default()
+    };
+    for t in &decoded {
+        context
+            .targets
+            .extend(scanner.collect_global_addresses(&t.instructions));
+        for (offset, command) in scanner.collect_threads(&t.instructions) {
+            context.threads.entry(offset).or_insert(command);
+        }
+        for (index, command) in scanner.collect_missions(&t.instructions) {
+            context.missions.entry(index).or_insert(command);
+        }
+        context
+            .globals
+            .extend(scanner.collect_globals(&t.instructions));
+    }
+
+    let scripts = run_jobs(
+        options.jobs,
+        scripts.iter().zip(decoded).collect(),
+        |(scr, t)| {
+            let mut ir = dasm.run(
+                t.instructions,
+                t.diagnostics,
+                scr.script_type,
+                scr.base_offset,
+                scr.mission_index,
+                &context,
+            );
+            ir.unreached = t.data;
+            ir
+        },
+    );
+
+    Disassembly {
+        header: loaded.header,
+        scripts,
+        context,
+        definitions,
+        warnings: loaded.warnings,
+    }
+}
+
+impl Disassembly {
+    /// File names of the scripts with the given extension. Missions are prefixed with their index
+    /// (mission_05_<name>) to list in the mission table order, other scripts sharing a name
+    /// are numbered in the loading order (MAIN, streamed scripts)
+    pub fn file_names(&self, extension: &str) -> Vec<String> {
+        let mut used = HashSet::new();
+        self.scripts
+            .iter()
+            .map(|ir| {
+                let stem = get_file_stem(ir);
+                let mut file_name = format!("{}.{}", stem, extension);
+                let mut count = 0;
+                while used.contains(&file_name) {
+                    count += 1;
+                    file_name = format!("{}_{}.{}", stem, count, extension);
+                }
+                used.insert(file_name.clone());
+                file_name
+            })
+            .collect()
+    }
+}
+
+/// Opcodes of the commands sharing their name with another one
+fn get_aliases(definitions: &HashMap<Opcode, Command>) -> HashSet<Opcode> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for command in definitions.values() {
+        *counts.entry(command.name.as_str()).or_default() += 1;
+    }
+    definitions
+        .iter()
+        .filter(|(_, command)| counts[command.name.as_str()] > 1)
+        .map(|(id, _)| *id)
+        .collect()
+}
+
+fn get_file_stem(ir: &IR) -> String {
+    match ir.mission_index {
+        // unnamed missions are already called mission_<index>
+        Some(index) if ir.name == format!("mission_{}", index) => format!("mission_{:02}", index),
+        Some(index) => format!("mission_{:02}_{}", index, ir.name),
+        None => ir.name.clone(),
+    }
+}
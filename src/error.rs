@@ -0,0 +1,172 @@
+use crate::loader::MissionError;
+use itertools::Itertools;
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum Error {
+    /// reading or writing a file failed
+    Io { path: String, source: io::Error },
+    /// the command library is not a valid JSON
+    Library {
+        path: String,
+        source: serde_json::Error,
+    },
+    /// the command library lacks a command the loader relies on
+    Definitions { message: String },
+    /// a header structure can't be read, offset points at the start of the structure
+    Header { offset: u32, message: String },
+    /// the archive with streamed scripts is damaged or incomplete
+    Archive { path: String, message: String },
+    /// the code does not follow the expected layout
+    Parse { offset: u32, message: String },
+    /// the operation is not available for the target game
+    Unsupported(String),
+    /// the output directory has files that would be overwritten
+    OutputNotEmpty { path: String },
+    /// --force would delete a file of the output directory that the previous run did not write
+    OutputForeignFile { path: String },
+    /// a line of the disassembly can't be compiled back
+    Assembly {
+        path: String,
+        line: usize,
+        message: String,
+    },
+}
+
+impl Error {
+    pub fn io(path: &str, source: io::Error) -> Self {
+        Error::Io {
+            path: String::from(path),
+            source,
+        }
+    }
+
+    pub fn header(offset: u32, message: &str) -> Self {
+        Error::Header {
+            offset,
+            message: String::from(message),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {}", path, source),
+            Error::Library { path, source } => {
+                write!(f, "Can't parse the library {}: {}", path, source)
+            }
+            Error::Definitions { message } => write!(f, "{}", message),
+            Error::Header { offset, message } => write!(f, "{} at {}", message, offset),
+            Error::Archive { path, message } => write!(f, "{}: {}", path, message),
+            Error::Parse { offset, message } => write!(f, "{} at {}", message, offset),
+            Error::Unsupported(message) => write!(f, "{}", message),
+            Error::OutputNotEmpty { path } => write!(
+                f,
+                "Output directory {} is not empty, use --force to overwrite it",
+                path
+            ),
+            Error::OutputForeignFile { path } => write!(
+                f,
+                "{} was not written by crust, remove it or choose another output directory",
+                path
+            ),
+            Error::Assembly {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", path, line, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Library { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Problem that does not stop the loading, returned next to the result for the caller to report
+#[derive(Debug)]
+pub enum Warning {
+    /// an entry of the mission table points outside of the mission area, the mission is skipped
+    Mission(MissionError),
+    /// the Sanny Builder footer lists custom opcodes the library does not have
+    UnknownCustomOpcodes { opcodes: Vec<u16>, footer: String },
+    /// the allocation segment disagrees with the size of the globals segment
+    GlobalSpaceSize { header: u32, allocation: u32 },
+    /// an external script exceeds the largest size given in the externals segment
+    ExternalSize { name: String, largest: u32 },
+    /// the externals segment lists scripts, but no archive was given to read them from
+    MissingImg { count: usize },
+    /// a global variable outside of the variable space of the header
+    GlobalOutsideSpace {
+        script: String,
+        offset: u32,
+        global: u16,
+        count: u32,
+    },
+    /// a command that is not a condition has the negation bit set
+    StrayNegation {
+        script: String,
+        offset: u32,
+        name: String,
+    },
+    /// MAIN is addressed with absolute offsets, but has relative ones
+    RelativeOffsetsInMain,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::Mission(e) => write!(f, "{}, skipped", e),
+            Warning::UnknownCustomOpcodes { opcodes, footer } => write!(
+                f,
+                "Custom opcodes {} ({}) are not found in the library",
+                opcodes.iter().map(|op| format!("{:04X}", op)).join(", "),
+                footer
+            ),
+            Warning::GlobalSpaceSize { header, allocation } => write!(
+                f,
+                "Global variable space size {} does not match the header value {}",
+                header, allocation
+            ),
+            Warning::ExternalSize { name, largest } => write!(
+                f,
+                "External script {} is larger than the largest streamed script size {}",
+                name, largest
+            ),
+            Warning::MissingImg { count } => write!(
+                f,
+                "{} external scripts skipped, script.img is not provided",
+                count
+            ),
+            Warning::GlobalOutsideSpace {
+                script,
+                offset,
+                global,
+                count,
+            } => write!(
+                f,
+                "Global variable {} in {} at {} is outside of the variable space ({} variables)",
+                global, script, offset, count
+            ),
+            Warning::StrayNegation {
+                script,
+                offset,
+                name,
+            } => write!(
+                f,
+                "Negated command {} in {} at {} is not a condition",
+                name, script, offset
+            ),
+            Warning::RelativeOffsetsInMain => {
+                write!(f, "Relative offsets found in the MAIN script")
+            }
+        }
+    }
+}
@@ -0,0 +1,21 @@
+use scoped_threadpool::Pool;
+
+/// Runs the job for every item on the given number of threads and returns the results in the
+/// order of the items, a single job runs in the calling thread.
+/// Every job writes to its own slot, so the workers share no locks
+pub fn run_jobs<T: Send, R: Send>(jobs: u32, items: Vec<T>, job: impl Fn(T) -> R + Sync) -> Vec<R> {
+    let mut results: Vec<Option<R>> = items.iter().map(|_| None).collect();
+    if jobs > 1 {
+        Pool::new(jobs).scoped(|scoped| {
+            for (item, slot) in items.into_iter().zip(results.iter_mut()) {
+                let job = &job;
+                scoped.execute(move || *slot = Some(job(item)));
+            }
+        });
+    } else {
+        for (item, slot) in items.into_iter().zip(results.iter_mut()) {
+            *slot = Some(job(item));
+        }
+    }
+    results.into_iter().flatten().collect()
+}
@@ -0,0 +1,27 @@
+//! Disassembler for the compiled scripts (SCM) of the GTA games.
+//!
+//! [`disassemble`] loads a script file, splits it into MAIN, missions and external scripts
+//! and decodes each of them into an [`IR`] that can be written out in a text form
+//! ([`disassembler::sanny`], [`disassembler::js`], [`disassembler::json`]).
+//! The [`assembler`] compiles that text back into a script file.
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod assembler;
+pub mod disassembler;
+mod disassembly;
+pub mod error;
+pub mod jobs;
+pub mod library;
+pub mod loader;
+mod parser;
+mod platform;
+pub mod types;
+
+pub use disassembler::{GlobalContext, IR};
+pub use disassembly::{disassemble, Disassembly, Input, Options};
+pub use error::{Error, Warning};
+pub use library::Library;
+pub use loader::{load, ScriptKind};
+pub use parser::Diagnostic;
+pub use platform::{get_parser, Game};
+pub use types::Instruction;
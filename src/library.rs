@@ -1,6 +1,7 @@
 use serde::{Deserialize, Deserializer};
 use std::collections::HashMap;
 
+use crate::error::Error;
 use crate::types;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -10,6 +11,7 @@ pub enum CommandParamType {
     String,
     Boolean,
     Label,
+    Model,
     Arguments,
     Vector(usize),
     Any(String),
@@ -47,9 +49,8 @@ impl<'de> Deserialize<'de> for CommandParamType {
     {
         match String::deserialize(deserializer).as_deref() {
             Ok("float") => Ok(Self::Float),
-            Ok("int" | "model_any" | "model_char" | "model_object" | "model_vehicle") => {
-                Ok(Self::Int)
-            }
+            Ok("int") => Ok(Self::Int),
+            Ok("model_any" | "model_char" | "model_object" | "model_vehicle") => Ok(Self::Model),
             Ok("label") => Ok(Self::Label),
             Ok("string" | "gxt_key" | "zone_key") => Ok(Self::String),
             Ok("bool" | "boolean") => Ok(Self::Boolean),
@@ -164,10 +165,12 @@ pub struct Library {
 }
 
 impl Library {
-    pub fn new(path: &str) -> Option<Self> {
-        let content = std::fs::read_to_string(path).unwrap_or_default();
-        let library = serde_json::from_str::<Library>(&content).ok()?;
-        Some(library)
+    pub fn new(path: &str) -> Result<Self, Error> {
+        let content = std::fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        serde_json::from_str::<Library>(&content).map_err(|source| Error::Library {
+            path: String::from(path),
+            source,
+        })
     }
 
     pub fn to_map(&self) -> HashMap<types::Opcode, Command> {
@@ -180,6 +183,19 @@ impl Library {
 
         map
     }
+
+    /// Same as to_map, but commands from the given extension take precedence
+    pub fn to_map_preferring(&self, extension: &str) -> HashMap<types::Opcode, Command> {
+        let mut map = self.to_map();
+        let preferred = self.extensions.iter().filter(|ext| ext.name == extension);
+        for command in preferred.flat_map(|ext| ext.commands.iter()) {
+            if let Some(id) = command.id {
+                map.insert(id, command.clone());
+            }
+        }
+
+        map
+    }
 }
 
 fn convert_to_number<'de, D>(deserializer: D) -> Result<Option<u16>, D::Error>
@@ -199,25 +215,21 @@ fn convert_platform<'de, D>(deserializer: D) -> Result<Vec<Platform>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let res = match Vec::deserialize(deserializer) {
+    match Vec::deserialize(deserializer) {
         Ok(x) => x
             .iter()
-            .try_fold(vec![], |mut acc: Vec<Platform>, el: &String| {
-                match el.as_str() {
-                    "any" => acc.push(Platform::Any),
-                    "pc" => acc.push(Platform::PC),
-                    "console" => acc.push(Platform::Console),
-                    "mobile" => acc.push(Platform::Mobile),
-                    _ => {
-                        panic!("Unknown platform name {el}");
-                    }
-                };
-                Some(acc)
+            .map(|el: &String| match el.as_str() {
+                "any" => Ok(Platform::Any),
+                "pc" => Ok(Platform::PC),
+                "console" => Ok(Platform::Console),
+                "mobile" => Ok(Platform::Mobile),
+                _ => Err(serde::de::Error::custom(format!(
+                    "Unknown platform name {el}"
+                ))),
             })
-            .unwrap_or_default(),
-        _ => vec![],
-    };
-    Ok(res)
+            .collect(),
+        _ => Ok(vec![]),
+    }
 }
 
 fn convert_version<'de, D>(deserializer: D) -> Result<Vec<Version>, D::Error>
@@ -253,3 +265,42 @@ where
         x => x,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Library with an extension per name, each defining command 0001 named after the extension
+    fn library(extensions: &[&str]) -> Library {
+        let extensions = extensions
+            .iter()
+            .map(|name| {
+                format!(
+                    r#"{{"name": "{name}", "commands": [{{"id": "0001", "name": "{name}_COMMAND", "num_params": 0}}]}}"#
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let json = format!(
+            r#"{{"meta": {{"last_update": 0, "url": "", "version": ""}}, "extensions": [{extensions}]}}"#
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn preferred_extension_wins() {
+        for extensions in [["default", "CLEO"], ["CLEO", "default"]] {
+            let map = library(&extensions).to_map_preferring("CLEO");
+            assert_eq!(map[&1].name, "CLEO_COMMAND");
+        }
+        let map = library(&["CLEO", "default"]).to_map();
+        assert_eq!(map[&1].name, "default_COMMAND");
+    }
+
+    #[test]
+    fn unknown_platform_is_an_error() {
+        let json = r#"{"id": "0001", "name": "WAIT", "num_params": 1, "platforms": ["watch"]}"#;
+        let error = serde_json::from_str::<Command>(json).unwrap_err();
+        assert!(error.to_string().contains("Unknown platform name watch"));
+    }
+}
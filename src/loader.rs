@@ -1,3 +1,4 @@
+use crate::error::{Error, Warning};
 use crate::library::Command;
 // use crate::definitions;
 use crate::platform;
@@ -7,10 +8,17 @@ use crate::types::*;
 use byteorder::{LittleEndian, ReadBytesExt};
 use io::Cursor;
 use std::collections::HashMap;
-use std::{fs, io};
+use std::io::Read;
+use std::{fmt, fs, io, path};
 
+const GLOBALS_SEG: usize = 0;
+const MODELS_SEG: usize = 1;
 const MISSIONS_SEG: usize = 2;
 const EXTERNALS_SEG: usize = 3;
+const ALLOCATION_SEG: usize = 5;
+
+const CLEO_EXTENSIONS: [&str; 4] = ["cs", "cm", "cs3", "cs4"];
+const FOOTER_SIGNATURE: &[u8] = b"__SBFTR";
 
 struct ScriptFile {
     code: ScriptChunk,
@@ -34,103 +42,545 @@ pub struct Script {
     pub chunk: ScriptChunk,
     pub script_type: ScriptType,
     pub base_offset: u32,
+    /// position in the mission table of the header
+    pub mission_index: Option<usize>,
 }
 
 impl Script {
-    fn new(chunk: ScriptChunk, script_type: ScriptType, base_offset: u32) -> Self {
+    fn new(
+        chunk: ScriptChunk,
+        script_type: ScriptType,
+        base_offset: u32,
+        mission_index: Option<usize>,
+    ) -> Self {
         Self {
             chunk,
             script_type,
             base_offset,
+            mission_index,
+        }
+    }
+}
+
+/// Global variable space reserved by the first segment
+pub struct Globals {
+    pub target_game: u8,
+    /// offset of the first global variable
+    pub offset: u32,
+    /// size of the variable space in bytes
+    pub size: u32,
+}
+impl Globals {
+    fn new(chunk: &[u8], offset: u32) -> Result<Self, Error> {
+        let target_game = *chunk
+            .first()
+            .ok_or_else(|| Error::header(offset, "Can't read the globals segment"))?;
+        Ok(Self {
+            target_game,
+            offset: offset + 1,
+            size: chunk.len() as u32 - 1,
+        })
+    }
+
+    pub fn count(&self) -> u32 {
+        self.size / 4
+    }
+
+    pub fn contains(&self, offset: u32) -> bool {
+        offset >= self.offset && offset < self.offset + self.size
+    }
+}
+
+pub struct Header {
+    pub globals: Globals,
+    /// names of the used objects, negative model ids are indices in this table
+    pub models: Vec<String>,
+    pub missions: Missions,
+}
+impl Header {
+    /// None for the names the table repeats, they don't tell the ids apart
+    pub fn get_model_name(&self, id: i32) -> Option<&str> {
+        match id {
+            id if id < 0 => self
+                .models
+                .get(id.unsigned_abs() as usize)
+                .filter(|name| self.models.iter().filter(|m| m == name).count() == 1)
+                .map(|x| x.as_str()),
+            _ => None,
+        }
+    }
+}
+
+fn read_models(chunk: &[u8], offset: u32) -> Result<Vec<String>, Error> {
+    let mut cursor = Cursor::new(chunk);
+    cursor.set_position(1); // todo: assert segment id?
+
+    let read_err = |_| Error::header(offset, "Can't read the models segment");
+    let num_models = cursor.read_u32::<LittleEndian>().map_err(read_err)?;
+    let mut models = Vec::new();
+    for _ in 0..num_models {
+        models.push(read_name(&mut cursor, 24).map_err(read_err)?);
+    }
+    Ok(models)
+}
+
+#[derive(Debug)]
+pub enum MissionError {
+    OutOfBounds {
+        index: usize,
+        offset: u32,
+        file_size: u32,
+    },
+    InsideMain {
+        index: usize,
+        offset: u32,
+        main_size: u32,
+    },
+}
+
+impl fmt::Display for MissionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MissionError::OutOfBounds {
+                index,
+                offset,
+                file_size,
+            } => write!(
+                f,
+                "Mission {} at {} is outside of the file ({} bytes)",
+                index, offset, file_size
+            ),
+            MissionError::InsideMain {
+                index,
+                offset,
+                main_size,
+            } => write!(
+                f,
+                "Mission {} at {} is inside the MAIN area ({} bytes)",
+                index, offset, main_size
+            ),
         }
     }
 }
 
-struct Missions {
-    main_size: u32,
-    missions: Vec<u32>,
+pub struct Mission {
+    pub index: usize,
+    pub start: u32,
+    pub end: u32,
+}
+
+/// Mission table of the header
+pub struct Missions {
+    /// end of the MAIN area, from the start of file
+    pub main_size: u32,
+    pub largest_mission: u32,
+    pub exclusive_missions: u16,
+    pub highest_local_var: Option<u32>,
+    /// mission offsets in the order of the table
+    pub offsets: Vec<u32>,
     file_size: u32,
-    current: usize,
 }
 impl Missions {
-    fn new(chunk: &[u8], file_size: u32) -> Self {
+    fn new(
+        chunk: &[u8],
+        offset: u32,
+        file_size: u32,
+        game: &platform::Game,
+    ) -> Result<Self, Error> {
         let mut cursor = Cursor::new(chunk);
-        cursor.set_position(1); // todo: assert segment id?
+        match game {
+            // the Stories mission table is not a segment
+            platform::Game::LCS | platform::Game::VCS => {}
+            _ => cursor.set_position(1), // todo: assert segment id?
+        }
 
-        let main_size = cursor.read_u32::<LittleEndian>().unwrap();
-        let _largest_mission = cursor.read_u32::<LittleEndian>().unwrap();
-        let num_missions = cursor.read_u16::<LittleEndian>().unwrap();
-        let _num_exclusive_missions = cursor.read_u16::<LittleEndian>().unwrap();
-        let mut missions = Vec::new();
+        let read_err = |_| Error::header(offset, "Can't read the missions segment");
+        let main_size = cursor.read_u32::<LittleEndian>().map_err(read_err)?;
+        let largest_mission = cursor.read_u32::<LittleEndian>().map_err(read_err)?;
+        let num_missions = cursor.read_u16::<LittleEndian>().map_err(read_err)?;
+        let exclusive_missions = cursor.read_u16::<LittleEndian>().map_err(read_err)?;
+        let highest_local_var = match game {
+            platform::Game::SA | platform::Game::LCS | platform::Game::VCS => {
+                Some(cursor.read_u32::<LittleEndian>().map_err(read_err)?)
+            }
+            _ => None,
+        };
+        let mut offsets = Vec::new();
         for _ in 0..num_missions {
-            missions.push(cursor.read_u32::<LittleEndian>().unwrap())
+            offsets.push(cursor.read_u32::<LittleEndian>().map_err(read_err)?)
         }
 
-        Self {
+        Ok(Self {
             main_size,
-            missions,
+            largest_mission,
+            exclusive_missions,
+            highest_local_var,
+            offsets,
             file_size,
-            current: 0,
+        })
+    }
+
+    fn validate(&self, index: usize, offset: u32) -> Result<(), MissionError> {
+        if offset > self.file_size {
+            return Err(MissionError::OutOfBounds {
+                index,
+                offset,
+                file_size: self.file_size,
+            });
         }
+        if offset < self.main_size {
+            return Err(MissionError::InsideMain {
+                index,
+                offset,
+                main_size: self.main_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Entries of the table pointing outside of the mission area
+    pub fn get_errors(&self) -> Vec<MissionError> {
+        self.offsets
+            .iter()
+            .enumerate()
+            .filter_map(|(index, &offset)| self.validate(index, offset).err())
+            .collect()
+    }
+
+    /// Bounds of the valid missions in file order. Each mission ends where the next unique offset starts,
+    /// entries sharing an offset are represented by the lowest mission index
+    pub fn get_bounds(&self) -> Vec<Mission> {
+        let mut offsets: Vec<(u32, usize)> = self
+            .offsets
+            .iter()
+            .enumerate()
+            .filter(|&(index, &offset)| self.validate(index, offset).is_ok())
+            .map(|(index, &offset)| (offset, index))
+            .collect();
+        offsets.sort_unstable();
+        offsets.dedup_by_key(|(offset, _)| *offset);
+
+        let ends = offsets
+            .iter()
+            .skip(1)
+            .map(|(offset, _)| *offset)
+            .chain(std::iter::once(self.file_size));
+        offsets
+            .iter()
+            .zip(ends)
+            .map(|(&(start, index), end)| Mission { index, start, end })
+            .collect()
     }
 }
-impl Iterator for Missions {
-    type Item = (u32, u32);
-    fn next(&mut self) -> Option<Self::Item> {
-        let offset = self.missions.get(self.current)?;
-        self.current += 1;
-        // todo: missions can be unordered, need to sort offsets first
-        let end = if self.current == self.missions.len() {
-            self.file_size
-        } else {
-            self.missions[self.current]
-        };
-        Some((*offset, end))
+
+struct Allocation {
+    global_var_space_size: u32,
+    #[allow(dead_code)]
+    allocated_externals: u8,
+}
+impl Allocation {
+    fn new(chunk: &[u8], offset: u32) -> Result<Self, Error> {
+        let mut cursor = Cursor::new(chunk);
+        cursor.set_position(1); // todo: assert segment id?
+
+        let read_err = |_| Error::header(offset, "Can't read the allocation segment");
+        let global_var_space_size = cursor.read_u32::<LittleEndian>().map_err(read_err)?;
+        let allocated_externals = cursor.read_u8().map_err(read_err)?;
+        Ok(Self {
+            global_var_space_size,
+            allocated_externals,
+        })
     }
 }
 
-struct Externals {}
+/// Trailing block appended by Sanny Builder to compiled CLEO scripts:
+/// footer data, its size and the signature at the very end of file
+pub struct CleoFooter {
+    pub source_hash: u32,
+    pub compiler_version: u32,
+    pub custom_opcodes: Vec<types::Opcode>,
+}
+impl CleoFooter {
+    /// Returns the footer and the size of the code that precedes it
+    fn new(chunk: &[u8]) -> Result<Option<(Self, u32)>, Error> {
+        if !chunk.ends_with(FOOTER_SIGNATURE) {
+            return Ok(None);
+        }
+        let signature_offset = (chunk.len() - FOOTER_SIGNATURE.len()) as u32;
+        let size_offset = signature_offset
+            .checked_sub(4)
+            .ok_or_else(|| Error::header(signature_offset, "Can't read the script footer"))?;
+        let mut cursor = Cursor::new(chunk);
+        cursor.set_position(size_offset as u64);
+        let footer_size = cursor
+            .read_u32::<LittleEndian>()
+            .map_err(|_| Error::header(size_offset, "Can't read the script footer"))?;
+        let code_size = size_offset
+            .checked_sub(footer_size)
+            .ok_or_else(|| Error::header(size_offset, "Invalid script footer size"))?;
+
+        let read_err = |_| Error::header(code_size, "Can't read the script footer");
+        let size_offset = size_offset as usize;
+
+        let mut cursor = Cursor::new(&chunk[code_size as usize..size_offset]);
+        let source_hash = cursor.read_u32::<LittleEndian>().map_err(read_err)?;
+        let compiler_version = cursor.read_u32::<LittleEndian>().map_err(read_err)?;
+        let num_opcodes = cursor.read_u16::<LittleEndian>().map_err(read_err)?;
+        let mut custom_opcodes = Vec::new();
+        for _ in 0..num_opcodes {
+            custom_opcodes.push(cursor.read_u16::<LittleEndian>().map_err(read_err)?);
+        }
+
+        Ok(Some((
+            Self {
+                source_hash,
+                compiler_version,
+                custom_opcodes,
+            },
+            code_size,
+        )))
+    }
+}
+impl fmt::Display for CleoFooter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [major, minor, patch, build] = self.compiler_version.to_be_bytes();
+        write!(
+            f,
+            "source {:08X}, compiler {}.{}.{}.{}",
+            self.source_hash, major, minor, patch, build
+        )
+    }
+}
+
+pub fn is_cleo_script(input_file: &path::Path) -> bool {
+    input_file
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| CLEO_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+fn load_cleo(
+    mut chunk: ScriptChunk,
+    defs: &HashMap<types::Opcode, Command>,
+    warnings: &mut Vec<Warning>,
+) -> Result<Vec<Script>, Error> {
+    if let Some((footer, code_size)) = CleoFooter::new(&chunk)? {
+        let unknown: Vec<types::Opcode> = footer
+            .custom_opcodes
+            .iter()
+            .filter(|op| !defs.contains_key(op))
+            .copied()
+            .collect();
+        if !unknown.is_empty() {
+            warnings.push(Warning::UnknownCustomOpcodes {
+                opcodes: unknown,
+                footer: footer.to_string(),
+            });
+        }
+        chunk.truncate(code_size as usize);
+    }
+    Ok(vec![Script::new(chunk, ScriptType::CLEO, 0, None)])
+}
+
+const IMG_SECTOR_SIZE: u32 = 2048;
+
+/// Streamed script listed in the externals segment
+pub struct External {
+    pub name: String,
+    /// offset of the script in the archive as written by the compiler,
+    /// the script itself is found by name in the archive directory
+    pub offset: u32,
+    pub size: u32,
+}
+
+struct Externals {
+    largest_script: u32,
+    entries: std::vec::IntoIter<External>,
+}
 impl Externals {
-    fn new(_chunk: &[u8]) -> Self {
-        unimplemented!()
+    fn new(chunk: &[u8], offset: u32) -> Result<Self, Error> {
+        let mut cursor = Cursor::new(chunk);
+        cursor.set_position(1); // todo: assert segment id?
+
+        let read_err = |_| Error::header(offset, "Can't read the externals segment");
+        let largest_script = cursor.read_u32::<LittleEndian>().map_err(read_err)?;
+        let num_scripts = cursor.read_u32::<LittleEndian>().map_err(read_err)?;
+        let mut entries = Vec::new();
+        for _ in 0..num_scripts {
+            let name = read_name(&mut cursor, 20).map_err(read_err)?;
+            let offset = cursor.read_u32::<LittleEndian>().map_err(read_err)?;
+            let size = cursor.read_u32::<LittleEndian>().map_err(read_err)?;
+            entries.push(External { name, offset, size });
+        }
+
+        Ok(Self {
+            largest_script,
+            entries: entries.into_iter(),
+        })
     }
 }
 impl Iterator for Externals {
-    type Item = String;
+    type Item = External;
     fn next(&mut self) -> Option<Self::Item> {
-        unimplemented!()
+        self.entries.next()
     }
 }
 
-struct ImgArchive {}
+struct ImgEntry {
+    name: String,
+    offset: u32,
+    size: u32,
+}
+
+struct ImgArchive {
+    data: Vec<u8>,
+    entries: Vec<ImgEntry>,
+}
 impl ImgArchive {
-    fn new(_filename: String) -> Self {
-        unimplemented!()
+    fn new(filename: &path::Path) -> Result<Self, Error> {
+        let data = fs::read(filename).map_err(|e| Error::io(&filename.display().to_string(), e))?;
+        let entries = if data.starts_with(b"VER2") {
+            Self::read_v2_entries(&data)
+        } else {
+            let dir_file = filename.with_extension("dir");
+            let dir =
+                fs::read(&dir_file).map_err(|e| Error::io(&dir_file.display().to_string(), e))?;
+            Self::read_v1_entries(&dir)
+        }
+        .map_err(|_| Error::Archive {
+            path: filename.display().to_string(),
+            message: String::from("Invalid archive directory"),
+        })?;
+
+        Ok(Self { data, entries })
     }
-    fn extract(&self, _name: String) -> &[u8] {
-        unimplemented!()
+
+    fn read_v1_entries(dir: &[u8]) -> Result<Vec<ImgEntry>, io::Error> {
+        let mut cursor = Cursor::new(dir);
+        let mut entries = Vec::new();
+        while cursor.position() < dir.len() as u64 {
+            let offset = cursor.read_u32::<LittleEndian>()?;
+            let size = cursor.read_u32::<LittleEndian>()?;
+            let name = read_name(&mut cursor, 24)?;
+            entries.push(ImgEntry {
+                name,
+                offset: offset * IMG_SECTOR_SIZE,
+                size: size * IMG_SECTOR_SIZE,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn read_v2_entries(data: &[u8]) -> Result<Vec<ImgEntry>, io::Error> {
+        let mut cursor = Cursor::new(data);
+        cursor.set_position(4);
+        let num_entries = cursor.read_u32::<LittleEndian>()?;
+        let mut entries = Vec::new();
+        for _ in 0..num_entries {
+            let offset = cursor.read_u32::<LittleEndian>()?;
+            let streaming_size = cursor.read_u16::<LittleEndian>()?;
+            let archive_size = cursor.read_u16::<LittleEndian>()?;
+            let name = read_name(&mut cursor, 24)?;
+            let size = if archive_size != 0 {
+                archive_size
+            } else {
+                streaming_size
+            };
+            entries.push(ImgEntry {
+                name,
+                offset: offset * IMG_SECTOR_SIZE,
+                size: u32::from(size) * IMG_SECTOR_SIZE,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn extract(&self, name: &str) -> Option<&[u8]> {
+        let file_name = format!("{}.scm", name);
+        let entry = self
+            .entries
+            .iter()
+            .find(|e| e.name.eq_ignore_ascii_case(&file_name))?;
+        let start = entry.offset as usize;
+        let end = (entry.offset + entry.size) as usize;
+        self.data.get(start..end.min(self.data.len()))
     }
 }
 
-fn get_segments<'a>(
+fn read_name(cursor: &mut Cursor<&[u8]>, len: usize) -> Result<String, io::Error> {
+    let mut buf = vec![0; len];
+    cursor.read_exact(buf.as_mut_slice())?;
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(len);
+    Ok(String::from_utf8_lossy(&buf[..end]).to_string())
+}
+
+fn split_scripts(
+    script_file: &ScriptFile,
+    main_start: u32,
+    missions: &Missions,
+    warnings: &mut Vec<Warning>,
+) -> Result<Vec<Script>, Error> {
+    if missions.main_size < main_start || missions.main_size > script_file.size {
+        return Err(Error::Header {
+            offset: main_start,
+            message: format!("Invalid MAIN size {}", missions.main_size),
+        });
+    }
+    // a damaged entry only costs its own mission
+    warnings.extend(missions.get_errors().into_iter().map(Warning::Mission));
+
+    let main_script = script_file.extract(main_start, missions.main_size);
+    let mut scripts = vec![Script::new(
+        main_script.to_vec(),
+        ScriptType::MAIN,
+        main_start,
+        None,
+    )];
+
+    for mission in missions.get_bounds() {
+        // zero-length missions have nothing to disassemble
+        if mission.end > mission.start {
+            scripts.push(Script::new(
+                script_file.extract(mission.start, mission.end).to_vec(),
+                ScriptType::MISSION,
+                0,
+                Some(mission.index),
+            ));
+        }
+    }
+    Ok(scripts)
+}
+
+fn get_segments(
     chunk: &ScriptChunk,
     game: &platform::Game,
     defs: &HashMap<types::Opcode, Command>,
-) -> Vec<(u32, u32)> {
+) -> Result<Vec<(u32, u32)>, Error> {
     let mut offsets: Vec<(u32, u32)> = Vec::new();
     let (id, c) = defs
         .iter()
         .find(|(_id, c)| c.attrs.is_segment)
-        .expect("Can't find a command with attribute is_segment");
+        .ok_or_else(|| Error::Definitions {
+            message: String::from("Can't find a command with attribute is_segment"),
+        })?;
     let mut defs = HashMap::new();
-    defs.insert(id.clone(), c.clone());
+    defs.insert(*id, c.clone());
 
     let mut parser = platform::get_parser(game, chunk, &defs, 0);
     loop {
         match parser.next() {
             Some(inst) if inst.opcode != 0xFFFF => match inst.params[0].to_offset() {
                 Some(destination) if destination > 0 => {
-                    offsets.push((parser.get_parser().get_position(), destination as u32));
+                    let position = parser.get_parser().get_position();
+                    // segments only go forward, anything else is not a header
+                    if destination as u32 <= position || destination as usize > chunk.len() {
+                        return Err(Error::Parse {
+                            offset: inst.offset,
+                            message: format!("Invalid segment destination {}", destination),
+                        });
+                    }
+                    offsets.push((position, destination as u32));
                     parser.get_parser_as_mut().set_position(destination as u32);
                 }
                 _ => break,
@@ -139,66 +589,472 @@ fn get_segments<'a>(
             None => break,
         }
     }
-    offsets
+    Ok(offsets)
+}
+
+/// Stories scripts start with a fixed header instead of GOTO segments:
+/// the offset of the mission table followed by the offset of MAIN code
+fn read_stories_header(
+    script_file: &ScriptFile,
+    game: &platform::Game,
+) -> Result<(u32, Missions), Error> {
+    let mut cursor = Cursor::new(&script_file.code);
+    let read_err = |_| Error::header(0, "Can't read the script header");
+    let missions_offset = cursor.read_u32::<LittleEndian>().map_err(read_err)?;
+    let main_start = cursor.read_u32::<LittleEndian>().map_err(read_err)?;
+    if missions_offset >= script_file.size || main_start >= script_file.size {
+        return Err(Error::header(0, "Invalid header structure"));
+    }
+    let missions = Missions::new(
+        script_file.extract(missions_offset, script_file.size),
+        missions_offset,
+        script_file.size,
+        game,
+    )?;
+    Ok((main_start, missions))
+}
+
+/// Layout of the input file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptKind {
+    /// main.scm with a header, or a headerless script such as the streamed ones
+    Scm,
+    /// standalone CLEO script, optionally with the Sanny Builder footer
+    Cleo,
+}
+
+impl ScriptKind {
+    /// CLEO scripts are recognized by the file extension
+    pub fn detect(input_file: &path::Path) -> Self {
+        if is_cleo_script(input_file) {
+            ScriptKind::Cleo
+        } else {
+            ScriptKind::Scm
+        }
+    }
 }
 
+/// Scripts of the input file
+pub struct Loaded {
+    /// `None` when the file has no header to read: CLEO scripts, headerless scripts,
+    /// and the Stories games (LCS, VCS), whose fixed header only locates MAIN and the mission table
+    pub header: Option<Header>,
+    pub scripts: Vec<Script>,
+    /// problems found in the header that did not stop the loading
+    pub warnings: Vec<Warning>,
+}
+
+/// Splits the input file into scripts. Streamed scripts are read from the archive, if given
 pub fn load(
-    input_file: String,
+    input_file: impl AsRef<path::Path>,
+    img_file: Option<&path::Path>,
+    kind: ScriptKind,
     game: &platform::Game,
     defs: &HashMap<types::Opcode, Command>,
-) -> Result<Vec<Script>, String> {
+) -> Result<Loaded, Error> {
+    let input_file = input_file.as_ref();
     let chunk =
-        fs::read(&input_file).map_err(|_| format!("Can't read input file {}", input_file))?;
+        fs::read(input_file).map_err(|e| Error::io(&input_file.display().to_string(), e))?;
+    load_bytes(chunk, img_file, kind, game, defs)
+}
+
+/// Same as load, for a script file already read into memory
+pub fn load_bytes(
+    chunk: ScriptChunk,
+    img_file: Option<&path::Path>,
+    kind: ScriptKind,
+    game: &platform::Game,
+    defs: &HashMap<types::Opcode, Command>,
+) -> Result<Loaded, Error> {
+    let mut warnings = vec![];
 
-    let segments = get_segments(&chunk, game, defs);
+    if kind == ScriptKind::Cleo {
+        let scripts = load_cleo(chunk, defs, &mut warnings)?;
+        return Ok(Loaded {
+            header: None,
+            scripts,
+            warnings,
+        });
+    }
+
+    if let platform::Game::LCS | platform::Game::VCS = game {
+        let script_file = ScriptFile::new(chunk);
+        let (main_start, missions) = read_stories_header(&script_file, game)?;
+        let scripts = split_scripts(&script_file, main_start, &missions, &mut warnings)?;
+        return Ok(Loaded {
+            header: None,
+            scripts,
+            warnings,
+        });
+    }
+
+    let segments = get_segments(&chunk, game, defs)?;
     let script_file = ScriptFile::new(chunk);
 
     match segments.len() {
         0 => {
             let main_script = script_file.extract(0, script_file.size);
-            return Ok(vec![Script::new(
-                main_script.to_vec(),
-                ScriptType::EXTERNAL,
-                0,
-            )]);
+            return Ok(Loaded {
+                header: None,
+                scripts: vec![Script::new(
+                    main_script.to_vec(),
+                    ScriptType::EXTERNAL,
+                    0,
+                    None,
+                )],
+                warnings,
+            });
         }
-        1 | 2 => return Err(String::from("No missions segment found")),
+        1 | 2 => return Err(Error::header(0, "No missions segment found")),
         3 | 6 => {}
-        _ => return Err(String::from("Invalid header structure")),
+        _ => return Err(Error::header(0, "Invalid header structure")),
     }
-    let (offset, end) = segments.get(MISSIONS_SEG).unwrap();
-    let missions = Missions::new(script_file.extract(*offset, *end), script_file.size);
-    let (_, main_start) = segments.last().unwrap();
-    let main_script = script_file.extract(*main_start, missions.main_size);
-    let mut scripts = vec![Script::new(
-        main_script.to_vec(),
-        ScriptType::MAIN,
-        *main_start,
-    )];
+    let (offset, end) = segments[GLOBALS_SEG];
+    let globals = Globals::new(script_file.extract(offset, end), offset)?;
+    let (offset, end) = segments[MODELS_SEG];
+    let models = read_models(script_file.extract(offset, end), offset)?;
+    let (offset, end) = segments[MISSIONS_SEG];
+    let missions = Missions::new(
+        script_file.extract(offset, end),
+        offset,
+        script_file.size,
+        game,
+    )?;
+    let (_, main_start) = segments[segments.len() - 1];
+    let mut scripts = split_scripts(&script_file, main_start, &missions, &mut warnings)?;
+    let header = Header {
+        globals,
+        models,
+        missions,
+    };
 
-    for (start, end) in missions {
-        // todo: empty missions
-        if end > start {
-            scripts.push(Script::new(
-                script_file.extract(start, end).to_vec(),
-                ScriptType::MISSION,
-                0,
-            ));
+    if let Some((offset, end)) = segments.get(ALLOCATION_SEG) {
+        let allocation = Allocation::new(script_file.extract(*offset, *end), *offset)?;
+        if allocation.global_var_space_size != header.globals.size {
+            warnings.push(Warning::GlobalSpaceSize {
+                header: header.globals.size,
+                allocation: allocation.global_var_space_size,
+            });
         }
     }
     if let Some((offset, end)) = segments.get(EXTERNALS_SEG) {
-        let externals: Vec<String> = Externals::new(script_file.extract(*offset, *end)).collect();
-        if externals.len() > 0 {
-            let script_img = ImgArchive::new(String::from("script.img"));
-            for name in externals {
-                scripts.push(Script::new(
-                    script_img.extract(name).to_vec(),
-                    ScriptType::EXTERNAL,
-                    0,
-                ));
+        let externals = Externals::new(script_file.extract(*offset, *end), *offset)?;
+        let largest_script = externals.largest_script;
+        let externals: Vec<External> = externals.collect();
+        if let Some(external) = externals.iter().find(|e| e.size > largest_script) {
+            warnings.push(Warning::ExternalSize {
+                name: external.name.clone(),
+                largest: largest_script,
+            });
+        }
+        match img_file {
+            _ if externals.is_empty() => {}
+            None => warnings.push(Warning::MissingImg {
+                count: externals.len(),
+            }),
+            Some(img_file) => {
+                let script_img = ImgArchive::new(img_file)?;
+                for external in externals {
+                    let chunk =
+                        script_img
+                            .extract(&external.name)
+                            .ok_or_else(|| Error::Archive {
+                                path: img_file.display().to_string(),
+                                message: format!("Can't find {}", external.name),
+                            })?;
+                    let size = (external.size as usize).min(chunk.len());
+                    scripts.push(Script::new(
+                        chunk[..size].to_vec(),
+                        ScriptType::EXTERNAL,
+                        0,
+                        None,
+                    ));
+                }
             }
         }
     }
 
-    Ok(scripts)
+    Ok(Loaded {
+        header: Some(header),
+        scripts,
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    /// Mission table segment of GTA III with the given MAIN size and mission offsets
+    fn missions_table(main_size: u32, offsets: &[u32]) -> Vec<u8> {
+        let mut chunk = vec![0]; // segment id
+        chunk.write_u32::<LittleEndian>(main_size).unwrap();
+        chunk.write_u32::<LittleEndian>(0).unwrap(); // largest mission
+        chunk
+            .write_u16::<LittleEndian>(offsets.len() as u16)
+            .unwrap();
+        chunk.write_u16::<LittleEndian>(0).unwrap(); // exclusive missions
+        for &offset in offsets {
+            chunk.write_u32::<LittleEndian>(offset).unwrap();
+        }
+        chunk
+    }
+
+    #[test]
+    fn keeps_the_ids_of_repeated_models() {
+        let header = Header {
+            globals: Globals {
+                target_game: 0,
+                offset: 0,
+                size: 0,
+            },
+            models: ["", "CSPLAY", "DOOR", "CSPLAY"].map(String::from).to_vec(),
+            missions: Missions {
+                main_size: 0,
+                largest_mission: 0,
+                exclusive_missions: 0,
+                highest_local_var: None,
+                offsets: vec![],
+                file_size: 0,
+            },
+        };
+        assert_eq!(header.get_model_name(-2), Some("DOOR"));
+        assert_eq!(header.get_model_name(-1), None);
+        assert_eq!(header.get_model_name(-3), None);
+        assert_eq!(header.get_model_name(2), None);
+    }
+
+    #[test]
+    fn skips_invalid_missions() {
+        let script_file = ScriptFile::new(vec![0; 100]);
+        let table = missions_table(40, &[40, 200, 60, 10]);
+        let missions = Missions::new(&table, 0, script_file.size, &platform::Game::GTA3).unwrap();
+
+        let mut warnings = vec![];
+        let scripts = split_scripts(&script_file, 0, &missions, &mut warnings).unwrap();
+        assert!(matches!(
+            warnings[..],
+            [
+                Warning::Mission(MissionError::OutOfBounds { index: 1, .. }),
+                Warning::Mission(MissionError::InsideMain { index: 3, .. })
+            ]
+        ));
+        let missions: Vec<_> = scripts
+            .iter()
+            .map(|s| (s.mission_index, s.chunk.len()))
+            .collect();
+        assert_eq!(missions, [(None, 40), (Some(0), 20), (Some(2), 40)]);
+    }
+
+    /// Stories script of 80 bytes: the header, MAIN up to 40, the mission table and a mission at 60
+    fn stories_script(missions_offset: u32) -> ScriptFile {
+        let mut code = vec![];
+        code.write_u32::<LittleEndian>(missions_offset).unwrap();
+        code.write_u32::<LittleEndian>(8).unwrap(); // MAIN start
+        code.resize(40, 0);
+        code.write_u32::<LittleEndian>(40).unwrap(); // MAIN size
+        code.write_u32::<LittleEndian>(20).unwrap(); // largest mission
+        code.write_u16::<LittleEndian>(1).unwrap();
+        code.write_u16::<LittleEndian>(0).unwrap(); // exclusive missions
+        code.write_u32::<LittleEndian>(12).unwrap(); // highest local variable
+        code.write_u32::<LittleEndian>(60).unwrap();
+        code.resize(80, 0);
+        ScriptFile::new(code)
+    }
+
+    /// Name padded with zeros to the given length
+    fn padded(name: &str, len: usize) -> Vec<u8> {
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.resize(len, 0);
+        bytes
+    }
+
+    #[test]
+    fn reads_externals_segment() {
+        let mut chunk = vec![0]; // segment id
+        chunk.write_u32::<LittleEndian>(300).unwrap(); // largest script
+        chunk.write_u32::<LittleEndian>(2).unwrap();
+        for (name, offset, size) in [("PLANE", 0, 300), ("BALLS", 300, 120)] {
+            chunk.extend(padded(name, 20));
+            chunk.write_u32::<LittleEndian>(offset).unwrap();
+            chunk.write_u32::<LittleEndian>(size).unwrap();
+        }
+
+        let externals = Externals::new(&chunk, 0).unwrap();
+        assert_eq!(externals.largest_script, 300);
+        let entries: Vec<_> = externals.map(|e| (e.name, e.offset, e.size)).collect();
+        assert_eq!(
+            entries,
+            [
+                (String::from("PLANE"), 0, 300),
+                (String::from("BALLS"), 300, 120)
+            ]
+        );
+
+        // the count promises an entry that is not there
+        assert!(Externals::new(&chunk[..9], 0).is_err());
+    }
+
+    #[test]
+    fn reads_sa_header() {
+        let goto: Command = serde_json::from_str(
+            r#"{"id": "0002", "name": "GOTO", "num_params": 1, "input": [{"name": "offset", "type": "label"}], "attrs": {"is_segment": true}}"#,
+        )
+        .unwrap();
+        let defs = HashMap::from([(2, goto)]);
+        // every segment starts with a GOTO over it
+        let mut chunk = vec![];
+        let mut segment = |body: Vec<u8>| {
+            let end = chunk.len() + 7 + body.len();
+            chunk.extend([0x02, 0x00, 0x01]);
+            chunk.write_u32::<LittleEndian>(end as u32).unwrap();
+            chunk.extend(body);
+        };
+        segment([b's', 0, 0, 0, 0, 0, 0, 0, 0].to_vec());
+        let mut models = vec![0];
+        models.write_u32::<LittleEndian>(1).unwrap();
+        models.extend(padded("", 24));
+        segment(models);
+        // the missions segment of SA has the highest local variable
+        let mut missions = vec![0];
+        missions.write_u32::<LittleEndian>(160).unwrap(); // MAIN size
+        missions.write_u32::<LittleEndian>(10).unwrap(); // largest mission
+        missions.write_u16::<LittleEndian>(1).unwrap();
+        missions.write_u16::<LittleEndian>(0).unwrap(); // exclusive missions
+        missions.write_u32::<LittleEndian>(42).unwrap(); // highest local variable
+        missions.write_u32::<LittleEndian>(160).unwrap();
+        segment(missions);
+        let mut externals = vec![0];
+        externals.write_u32::<LittleEndian>(100).unwrap(); // largest script
+        externals.write_u32::<LittleEndian>(1).unwrap();
+        externals.extend(padded("PLANE", 20));
+        externals.write_u32::<LittleEndian>(0).unwrap();
+        externals.write_u32::<LittleEndian>(50).unwrap();
+        segment(externals);
+        segment(vec![0, 0, 0, 0, 0]);
+        // the allocation segment repeats the size of the global variable space
+        let mut allocation = vec![0];
+        allocation.write_u32::<LittleEndian>(12).unwrap();
+        allocation.push(1); // allocated externals
+        segment(allocation);
+        assert_eq!(chunk.len(), 149);
+        chunk.resize(170, 0x01);
+
+        let loaded = load_bytes(chunk, None, ScriptKind::Scm, &platform::Game::SA, &defs).unwrap();
+        let header = loaded.header.unwrap();
+        assert_eq!((header.globals.target_game, header.globals.size), (b's', 8));
+        assert_eq!(header.models, [""]);
+        assert_eq!(header.missions.highest_local_var, Some(42));
+        assert_eq!(header.missions.offsets, [160]);
+        let scripts: Vec<_> = loaded
+            .scripts
+            .iter()
+            .map(|s| (s.mission_index, s.chunk.len()))
+            .collect();
+        assert_eq!(scripts, [(None, 11), (Some(0), 10)]);
+        assert!(matches!(
+            loaded.warnings[..],
+            [
+                Warning::GlobalSpaceSize {
+                    header: 8,
+                    allocation: 12
+                },
+                Warning::MissingImg { count: 1 }
+            ]
+        ));
+    }
+
+    #[test]
+    fn reads_v1_archive() {
+        let mut dir = vec![];
+        dir.write_u32::<LittleEndian>(1).unwrap(); // offset in sectors
+        dir.write_u32::<LittleEndian>(1).unwrap(); // size in sectors
+        dir.extend(padded("plane.scm", 24));
+        let mut data = vec![0; 2 * IMG_SECTOR_SIZE as usize];
+        data[IMG_SECTOR_SIZE as usize] = 1;
+
+        let archive = ImgArchive {
+            entries: ImgArchive::read_v1_entries(&dir).unwrap(),
+            data,
+        };
+        let script = archive.extract("PLANE").unwrap();
+        assert_eq!((script.len(), script[0]), (IMG_SECTOR_SIZE as usize, 1));
+        assert!(archive.extract("BALLS").is_none());
+    }
+
+    #[test]
+    fn reads_v2_archive() {
+        let mut data = b"VER2".to_vec();
+        data.write_u32::<LittleEndian>(2).unwrap();
+        // an archive size of 0 falls back to the streaming size,
+        // the second entry claims more sectors than the file has
+        for (name, offset, streaming_size, archive_size) in
+            [("plane.scm", 1, 1, 0), ("balls.scm", 2, 1, 2)]
+        {
+            data.write_u32::<LittleEndian>(offset).unwrap();
+            data.write_u16::<LittleEndian>(streaming_size).unwrap();
+            data.write_u16::<LittleEndian>(archive_size).unwrap();
+            data.extend(padded(name, 24));
+        }
+        data.resize(3 * IMG_SECTOR_SIZE as usize, 0);
+        data[IMG_SECTOR_SIZE as usize] = 1;
+        data[2 * IMG_SECTOR_SIZE as usize] = 2;
+
+        let archive = ImgArchive {
+            entries: ImgArchive::read_v2_entries(&data).unwrap(),
+            data,
+        };
+        for (name, first_byte) in [("PLANE", 1), ("BALLS", 2)] {
+            let script = archive.extract(name).unwrap();
+            assert_eq!(
+                (script.len(), script[0]),
+                (IMG_SECTOR_SIZE as usize, first_byte)
+            );
+        }
+    }
+
+    #[test]
+    fn reads_stories_header() {
+        let script_file = stories_script(40);
+        let (main_start, missions) =
+            read_stories_header(&script_file, &platform::Game::VCS).unwrap();
+        assert_eq!(main_start, 8);
+        assert_eq!(missions.main_size, 40);
+        assert_eq!(missions.largest_mission, 20);
+        assert_eq!(missions.highest_local_var, Some(12));
+        assert_eq!(missions.offsets, [60]);
+
+        let mut warnings = vec![];
+        let scripts = split_scripts(&script_file, main_start, &missions, &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+        let scripts: Vec<_> = scripts
+            .iter()
+            .map(|s| (s.mission_index, s.base_offset, s.chunk.len()))
+            .collect();
+        assert_eq!(scripts, [(None, 8, 32), (Some(0), 0, 20)]);
+    }
+
+    #[test]
+    fn skips_invalid_stories_missions() {
+        let mut script_file = stories_script(40);
+        // the only mission points past the end of file
+        script_file.code[56..60].copy_from_slice(&100u32.to_le_bytes());
+        let (main_start, missions) =
+            read_stories_header(&script_file, &platform::Game::LCS).unwrap();
+
+        let mut warnings = vec![];
+        let scripts = split_scripts(&script_file, main_start, &missions, &mut warnings).unwrap();
+        assert_eq!(scripts.len(), 1);
+        assert!(matches!(
+            warnings[..],
+            [Warning::Mission(MissionError::OutOfBounds { index: 0, .. })]
+        ));
+    }
+
+    #[test]
+    fn rejects_stories_header_outside_of_file() {
+        let script_file = stories_script(80);
+        assert!(read_stories_header(&script_file, &platform::Game::LCS).is_err());
+    }
 }
@@ -1,22 +1,32 @@
-mod disassembler;
-mod library;
-mod loader;
-mod parser;
-mod platform;
-mod types;
-extern crate slugify;
-
-use clap::Parser;
-use disassembler::scanner;
-use library::Library;
-use scoped_threadpool;
-use std::fs;
-use std::sync::Mutex;
+use clap::{CommandFactory, Parser, ValueEnum};
+use crust::assembler::{self, Assembler, Manifest};
+use crust::disassembler::sanny::{self, Syntax};
+use crust::disassembler::{self, GlobalContext, IR};
+use crust::jobs::run_jobs;
+use crust::library::Command;
+use crust::loader::Header;
+use crust::types::{Opcode, ScriptType};
+use crust::{Disassembly, Error, Game, Input, Library, Options, ScriptKind};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::{fs, path, process, thread};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    /// {offset} NAME params, can be assembled back with --assemble
+    Text,
+    /// Sanny Builder source
+    Sanny,
+    /// CLEO Redux JavaScript
+    Js,
+    /// JSON with typed instruction params
+    Json,
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Input file to disassemble
+    /// Input file to disassemble, or the directory with the disassembly when using --assemble
     input_file: String,
 
     /// File with command definitions (.json)
@@ -24,56 +34,407 @@ struct Args {
 
     /// Target game
     #[arg(long)]
-    game: platform::Game,
+    game: Game,
+
+    /// Archive with streamed scripts (script.img)
+    #[arg(long)]
+    img: Option<String>,
+
+    /// Treat the input file as a CLEO script (implied by .cs/.cm/.cs3/.cs4 extensions)
+    #[arg(long)]
+    cleo: bool,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// Structure the control flow into if/else/while blocks (requires --format sanny)
+    #[arg(long)]
+    decompile: bool,
+
+    /// Write commands as class members (Car.Create) where the library has them (requires --format sanny)
+    #[arg(long)]
+    classes: bool,
+
+    /// Compile the disassembly back into the given script file (gta3, vc)
+    #[arg(long, value_name = "FILE")]
+    assemble: Option<String>,
+
+    /// Decode only the code reachable from the entry points, the rest is reported as data
+    #[arg(long)]
+    recursive: bool,
+
+    /// Explain why the data blocks could not be decoded as instructions (printed to stderr)
+    #[arg(long)]
+    diagnostics: bool,
+
+    /// Number of worker threads, 1 disassembles in the main thread
+    #[arg(long, default_value_t = get_default_jobs(), value_parser = clap::value_parser!(u32).range(1..))]
+    jobs: u32,
+
+    /// Output directory
+    #[arg(long, default_value = "out")]
+    out: String,
+
+    /// Replace the scripts of a previous run in the output directory
+    #[arg(long)]
+    force: bool,
+
+    /// Write all scripts into a single file instead of the output directory, - for stdout
+    #[arg(long, value_name = "FILE")]
+    combine: Option<String>,
+}
+
+fn get_extension(format: Format) -> &'static str {
+    match format {
+        Format::Js => "js",
+        Format::Json => "json",
+        _ => "txt",
+    }
+}
+
+fn print(
+    ir: IR,
+    context: &GlobalContext,
+    cli: &Args,
+    defs: &HashMap<Opcode, Command>,
+) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    let syntax = if cli.classes {
+        Syntax::Classes(defs)
+    } else {
+        Syntax::Opcodes
+    };
+    match cli.format {
+        Format::Js => {
+            let unstructured = disassembler::js::write(&ir, context, defs, &mut out)?;
+            if unstructured > 0 {
+                eprintln!(
+                    "Warning: {} jumps of {} can't be structured, they throw in the JavaScript export",
+                    unstructured, ir.name
+                );
+            }
+            Ok(())
+        }
+        Format::Json => disassembler::json::write(&ir, &mut out),
+        Format::Sanny if cli.decompile => sanny::write_decompiled(&ir, context, &syntax, &mut out),
+        Format::Sanny => sanny::write(&ir, context, &syntax, &mut out),
+        Format::Text => ir.write(context, &mut out),
+    }?;
+    Ok(out)
+}
+
+/// Sanny Builder compiles main.scm from MAIN with the DEFINE block of the header
+/// and the missions included after it
+fn with_header(header: &Header, missions: &[&String], main: Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    sanny::write_header(header, &mut out)?;
+    out.extend(main);
+    if !missions.is_empty() {
+        sanny::write_includes(missions, &mut out)?;
+    }
+    Ok(out)
+}
+
+/// File in the output directory listing the files of the run, one name per line
+const RUN_MARKER: &str = ".crust";
+
+/// Creates the output directory, a non-empty one is only cleared with --force.
+/// Clearing refuses to touch anything but the files listed in the marker of a previous run
+fn prepare_out_dir(dir: &str, force: bool) -> Result<(), Error> {
+    if let Ok(entries) = fs::read_dir(dir) {
+        let files = entries
+            .map(|e| e.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::io(dir, e))?;
+        if !files.is_empty() && !force {
+            return Err(Error::OutputNotEmpty {
+                path: String::from(dir),
+            });
+        }
+        // without a marker none of the files is known to be written by crust
+        let marker = path::Path::new(dir).join(RUN_MARKER);
+        let written: HashSet<path::PathBuf> = fs::read_to_string(&marker)
+            .unwrap_or_default()
+            .lines()
+            .map(|file_name| path::Path::new(dir).join(file_name))
+            .chain([marker])
+            .collect();
+        if let Some(file) = files.iter().find(|f| !written.contains(*f)) {
+            return Err(Error::OutputForeignFile {
+                path: file.display().to_string(),
+            });
+        }
+        for file in &files {
+            fs::remove_file(file).map_err(|e| Error::io(&file.display().to_string(), e))?;
+        }
+    }
+    fs::create_dir_all(dir).map_err(|e| Error::io(dir, e))
+}
+
+fn write_run_marker(dir: &str, file_names: &[&str]) -> Result<(), Error> {
+    let path = path::Path::new(dir).join(RUN_MARKER);
+    let content: String = file_names.iter().map(|f| format!("{}\n", f)).collect();
+    fs::write(&path, content).map_err(|e| Error::io(&path.to_string_lossy(), e))
+}
+
+/// Joins the scripts into one document, JSON scripts become an array
+fn write_combined(
+    path: &str,
+    format: Format,
+    scripts: Vec<(&String, Vec<u8>)>,
+) -> Result<(), Error> {
+    let mut out = vec![];
+    match format {
+        Format::Json => {
+            let documents: Vec<Vec<u8>> = scripts.into_iter().map(|(_, s)| s).collect();
+            out.extend(b"[\n");
+            out.extend(documents.join(&b",\n"[..]));
+            out.extend(b"]\n");
+        }
+        _ => {
+            for (file_name, script) in scripts {
+                out.extend(format!("// {}\n", file_name).as_bytes());
+                out.extend(script);
+                out.extend(b"\n");
+            }
+        }
+    }
+    match path {
+        "-" => io::stdout()
+            .write_all(&out)
+            .map_err(|e| Error::io("stdout", e)),
+        _ => fs::write(path, out).map_err(|e| Error::io(path, e)),
+    }
+}
+
+fn write_manifest(
+    dir: &str,
+    header: &Header,
+    files: Vec<(&String, ScriptType, Option<usize>)>,
+) -> Result<(), Error> {
+    let mut main = String::new();
+    let mut missions = HashMap::new();
+    for (file_name, script_type, mission_index) in files {
+        match (script_type, mission_index) {
+            (ScriptType::MAIN, _) => main = file_name.clone(),
+            (_, Some(index)) => {
+                missions.insert(index, file_name.clone());
+            }
+            _ => {}
+        }
+    }
+
+    let file_name = path::Path::new(dir)
+        .join(assembler::MANIFEST_FILE)
+        .to_string_lossy()
+        .to_string();
+    let mut f = fs::File::create(&file_name).map_err(|e| Error::io(&file_name, e))?;
+    Manifest::new(header, main, &missions)
+        .write(&mut f)
+        .map_err(|e| Error::io(&file_name, e))
+}
+
+fn assemble(cli: &Args, output_file: &str) -> Result<(), Error> {
+    let defs = Library::new(&cli.defs)?.to_map();
+    let assembler = Assembler::new(&cli.game, &defs)?;
+    let script = assembler.run(path::Path::new(&cli.input_file))?;
+    fs::write(output_file, script).map_err(|e| Error::io(output_file, e))
+}
 
-    // #[arg(long("out"), default_value_t=String::from("out"))]
-    // out_dir: String,
+fn get_default_jobs() -> u32 {
+    thread::available_parallelism().map_or(1, |n| n.get() as u32)
+}
+
+/// Rejects the options the output format has no use for
+fn validate(cli: &Args) -> Result<(), clap::Error> {
+    for (option, used) in [("--decompile", cli.decompile), ("--classes", cli.classes)] {
+        if used && !matches!(cli.format, Format::Sanny) {
+            return Err(Args::command().error(
+                clap::error::ErrorKind::ArgumentConflict,
+                format!("{} requires --format sanny", option),
+            ));
+        }
+    }
+    Ok(())
 }
 
 fn main() {
     let cli = Args::parse();
-    let game = &cli.game;
-    let defs = Library::new(&cli.defs)
-        .map(|x| x.to_map())
-        .unwrap_or_default();
-    let scripts = loader::load(cli.input_file, game, &defs).unwrap();
-    let mut pool = scoped_threadpool::Pool::new(4);
-    // temp
-    if fs::metadata("out").is_ok() {
-        fs::remove_dir_all("out").unwrap();
-    }
-    fs::create_dir_all("out").unwrap();
-
-    let global_context_mutex = Mutex::new(disassembler::GlobalContext::default());
-    let irs_mutex: Mutex<Vec<disassembler::IR>> = Mutex::new(vec![]);
-    let scanner = scanner::Scanner::new(&defs);
-    let dasm = disassembler::Disassembler::new(&defs, &scanner);
-
-    pool.scoped(|scoped| {
-        for scr in &scripts {
-            scoped.execute(|| {
-                let parser = platform::get_parser(game, &scr.chunk, &defs, scr.base_offset);
-                let instructions = parser.collect();
-
-                {
-                    let global_addresses = scanner.collect_global_addresses(&instructions);
-                    let mut global_context = global_context_mutex.lock().unwrap();
-                    (*global_context).targets.extend(global_addresses);
-                }
-
-                let ir = dasm.run(instructions, scr.script_type);
-                let mut irs = irs_mutex.lock().unwrap();
-                (*irs).push(ir);
-            });
+    if let Err(e) = validate(&cli) {
+        e.exit();
+    }
+    if let Err(e) = run(cli) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(cli: Args) -> Result<(), Error> {
+    if let Some(output_file) = &cli.assemble {
+        return assemble(&cli, output_file);
+    }
+    let input_file = path::Path::new(&cli.input_file);
+    let kind = if cli.cleo {
+        ScriptKind::Cleo
+    } else {
+        ScriptKind::detect(input_file)
+    };
+    let library = Library::new(&cli.defs)?;
+    let input = Input {
+        path: input_file,
+        img: cli.img.as_deref().map(path::Path::new),
+        kind,
+        game: cli.game.clone(),
+    };
+    let options = Options {
+        recursive: cli.recursive,
+        jobs: cli.jobs,
+    };
+    let disassembly = crust::disassemble(&library, &input, &options)?;
+    let file_names = disassembly.file_names(get_extension(cli.format));
+    // only the text format can be assembled back
+    let has_manifest = disassembly.header.is_some() && matches!(cli.format, Format::Text);
+    if cli.combine.is_none() {
+        prepare_out_dir(&cli.out, cli.force)?;
+        // listed before writing, so that --force clears the files of an interrupted run too
+        let mut written: Vec<&str> = file_names.iter().map(String::as_str).collect();
+        if has_manifest {
+            written.push(assembler::MANIFEST_FILE);
         }
-    });
+        write_run_marker(&cli.out, &written)?;
+    }
+    let Disassembly {
+        header,
+        scripts,
+        context,
+        definitions,
+        warnings,
+    } = disassembly;
 
-    let context = global_context_mutex.into_inner().unwrap();
-    pool.scoped(|scoped| {
-        for ir in irs_mutex.into_inner().unwrap() {
-            scoped.execute(|| {
-                ir.print(&context);
-            });
+    for warning in &warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    for ir in &scripts {
+        for warning in &ir.warnings {
+            eprintln!("Warning: {}", warning);
         }
+        for range in &ir.unreached {
+            eprintln!(
+                "Data block in {} at {} ({} bytes)",
+                ir.name,
+                range.start,
+                range.len()
+            );
+        }
+        if cli.diagnostics {
+            for diagnostic in &ir.diagnostics {
+                eprintln!("Diagnostic in {} at {}", ir.name, diagnostic);
+            }
+        }
+    }
+    let files: Vec<_> = file_names
+        .iter()
+        .zip(&scripts)
+        .map(|(file_name, ir)| (file_name, ir.script_type, ir.mission_index))
+        .collect();
+    let outputs = run_jobs(cli.jobs, scripts, |ir| {
+        print(ir, &context, &cli, &definitions)
     });
+
+    // the combined file has the missions after MAIN already
+    let missions: Vec<&String> = match cli.combine {
+        Some(_) => vec![],
+        None => files
+            .iter()
+            .filter(|(_, _, mission_index)| mission_index.is_some())
+            .map(|(file_name, _, _)| *file_name)
+            .collect(),
+    };
+    let mut scripts = vec![];
+    for (output, (file_name, script_type, _)) in outputs.into_iter().zip(&files) {
+        let path = match &cli.combine {
+            Some(path) => path.clone(),
+            None => path::Path::new(&cli.out)
+                .join(file_name)
+                .to_string_lossy()
+                .to_string(),
+        };
+        let output = match (&header, script_type) {
+            (Some(header), ScriptType::MAIN) if matches!(cli.format, Format::Sanny) => {
+                output.and_then(|main| with_header(header, &missions, main))
+            }
+            _ => output,
+        };
+        scripts.push((*file_name, output.map_err(|e| Error::io(&path, e))?));
+    }
+
+    if let Some(path) = &cli.combine {
+        return write_combined(path, cli.format, scripts);
+    }
+    for (file_name, script) in scripts {
+        let path = path::Path::new(&cli.out).join(file_name);
+        fs::write(&path, script).map_err(|e| Error::io(&path.to_string_lossy(), e))?;
+    }
+    match &header {
+        Some(header) if has_manifest => write_manifest(&cli.out, header, files),
+        Some(_) => {
+            eprintln!(
+                "Note: {} is only written with --format text, the output can't be assembled back",
+                assembler::MANIFEST_FILE
+            );
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory for the test in the temp directory of the system
+    fn temp_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("crust_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn refuses_to_clear_unknown_files() {
+        let dir = temp_dir("refuses_to_clear_unknown_files");
+        let script = path::Path::new(&dir).join("main.txt");
+        fs::write(&script, "").unwrap();
+        assert!(matches!(
+            prepare_out_dir(&dir, false),
+            Err(Error::OutputNotEmpty { .. })
+        ));
+        // a directory without the marker of a previous run is never cleared
+        assert!(matches!(
+            prepare_out_dir(&dir, true),
+            Err(Error::OutputForeignFile { .. })
+        ));
+        assert!(script.exists());
+    }
+
+    #[test]
+    fn force_clears_the_previous_run() {
+        let dir = temp_dir("force_clears_the_previous_run");
+        let script = path::Path::new(&dir).join("main.txt");
+        let notes = path::Path::new(&dir).join("notes.txt");
+        prepare_out_dir(&dir, false).unwrap();
+        write_run_marker(&dir, &["main.txt", assembler::MANIFEST_FILE]).unwrap();
+        fs::write(&script, "").unwrap();
+        fs::write(&notes, "").unwrap();
+        assert!(matches!(
+            prepare_out_dir(&dir, true),
+            Err(Error::OutputForeignFile { path }) if path == notes.display().to_string()
+        ));
+        assert!(script.exists() && notes.exists());
+
+        fs::remove_file(&notes).unwrap();
+        prepare_out_dir(&dir, true).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
 }
@@ -1,13 +1,47 @@
-use crate::library::Command;
+use crate::library::{Command, CommandParamType};
 use crate::types;
 use std::collections::HashMap;
-use std::io::Cursor;
+use std::fmt;
+use std::io::{self, Cursor};
+
+/// Why the bytes at the offset could not be decoded as an instruction
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostic {
+    /// offset in the address space of the script instructions
+    pub offset: u32,
+    pub opcode: Option<types::Opcode>,
+    /// index of the param being read and its type in the library
+    pub param: Option<(usize, CommandParamType)>,
+    /// data type byte read for the param
+    pub data_type: Option<u8>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.offset)?;
+        if let Some(opcode) = self.opcode {
+            write!(f, " opcode {:04X}", opcode)?;
+        }
+        if let Some((index, param_type)) = &self.param {
+            write!(f, " param {} expected {:?}", index, param_type)?;
+        }
+        if let Some(data_type) = self.data_type {
+            write!(f, " data type {:02X}", data_type)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
 
 pub struct Parser<'a> {
     pub cursor: Cursor<&'a types::ScriptChunk>,
     pub definitions: &'a HashMap<types::Opcode, Command>,
     pub size: u32,
     pub base_offset: u32,
+    /// what the current instruction has been decoded up to
+    pub attempt: Diagnostic,
+    /// failed attempts to decode an instruction
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 impl<'a> Parser<'a> {
@@ -21,6 +55,8 @@ impl<'a> Parser<'a> {
             size: chunk.len() as u32,
             cursor: Cursor::new(chunk),
             base_offset,
+            attempt: Diagnostic::default(),
+            diagnostics: vec![],
         }
     }
     pub fn get_position(&self) -> u32 {
@@ -30,8 +66,32 @@ impl<'a> Parser<'a> {
     pub fn set_position(&mut self, position: u32) {
         self.cursor.set_position(position as u64)
     }
+
+    /// Starts decoding an instruction at the position of the chunk
+    pub fn begin(&mut self, position: u32) {
+        self.attempt = Diagnostic {
+            offset: position + self.base_offset,
+            ..Default::default()
+        };
+    }
+
+    /// Keeps the error of the current instruction as a diagnostic
+    pub fn fail(&mut self, error: &io::Error) {
+        let message = match error.kind() {
+            io::ErrorKind::UnexpectedEof => String::from("Unexpected end of script"),
+            _ => error.to_string(),
+        };
+        self.diagnostics.push(Diagnostic {
+            message,
+            ..self.attempt.clone()
+        });
+    }
 }
-pub trait Parse<'a>: Iterator<Item = Box<types::Instruction>> {
+pub trait Parse<'a>: Iterator<Item = types::Instruction> {
     fn get_parser_as_mut(&mut self) -> &mut Parser<'a>;
     fn get_parser(&self) -> &Parser<'a>;
+    /// Decodes the instruction at the position of the chunk, without falling back to raw bytes
+    fn decode(&mut self, position: u32) -> io::Result<types::Instruction>;
+    /// Reads the byte at the position of the chunk as an invalid instruction
+    fn decode_raw(&mut self, position: u32) -> io::Result<types::Instruction>;
 }
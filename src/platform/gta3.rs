@@ -6,9 +6,8 @@ use crate::types::*;
 
 use byteorder::{LittleEndian, ReadBytesExt};
 use std::collections::HashMap;
-use std::convert::TryFrom;
 use std::io::Read;
-use std::{fmt, io, str};
+use std::{fmt, io};
 
 #[derive(Debug, Clone)]
 pub enum InstructionParam3 {
@@ -16,7 +15,7 @@ pub enum InstructionParam3 {
     RAW(u8),
     NUM32(i32),
     FLOAT(f32),
-    STR(String),
+    STR8([u8; 8]),
     GVAR(u16),
     LVAR(u16),
     OFFSET(i32),
@@ -34,30 +33,69 @@ pub enum DataType3 {
     LVAR,
 }
 
-impl TryFrom<u8> for DataType3 {
-    type Error = std::convert::Infallible;
-    fn try_from(data_type: u8) -> Result<Self, Self::Error> {
+impl DataType3 {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DataType3::EOL => "EOL",
+            DataType3::NUM8 => "NUM8",
+            DataType3::NUM16 => "NUM16",
+            DataType3::NUM32 => "NUM32",
+            DataType3::FLOAT => "FLOAT",
+            DataType3::STR8 => "STR8",
+            DataType3::GVAR => "GVAR",
+            DataType3::LVAR => "LVAR",
+        }
+    }
+}
+
+impl From<u8> for DataType3 {
+    fn from(data_type: u8) -> Self {
         match data_type {
-            0 => Ok(DataType3::EOL),
-            1 => Ok(DataType3::NUM32),
-            2 => Ok(DataType3::GVAR),
-            3 => Ok(DataType3::LVAR),
-            4 => Ok(DataType3::NUM8),
-            5 => Ok(DataType3::NUM16),
-            6 => Ok(DataType3::FLOAT),
-            _ => Ok(DataType3::STR8),
+            0 => DataType3::EOL,
+            1 => DataType3::NUM32,
+            2 => DataType3::GVAR,
+            3 => DataType3::LVAR,
+            4 => DataType3::NUM8,
+            5 => DataType3::NUM16,
+            6 => DataType3::FLOAT,
+            _ => DataType3::STR8,
         }
     }
 }
 
+/// Text of a fixed-size string up to the terminator. The compiler leaves garbage after it,
+/// which follows the text as hex bytes to keep it lossless: `"CSPLAY"[CC]`
+pub(crate) fn escape_str(buf: &[u8]) -> String {
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    let text: String = buf[..end]
+        .iter()
+        .map(|&b| match b {
+            b'"' | b'\\' => format!("\\{}", b as char),
+            0x20..=0x7E => (b as char).to_string(),
+            _ => format!("\\x{:02X}", b),
+        })
+        .collect();
+    let tail = buf.get(end + 1..).unwrap_or_default();
+    let tail_len = tail.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    if tail_len == 0 {
+        return format!("\"{}\"", text);
+    }
+    let hex: String = tail[..tail_len]
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    format!("\"{}\"[{}]", text, hex)
+}
+
 impl fmt::Display for InstructionParam3 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             InstructionParam3::EOL => write!(f, ""),
-            InstructionParam3::STR(d) => write!(f, "\"{}\"", d),
+            InstructionParam3::STR8(d) => write!(f, "{}", escape_str(d)),
             InstructionParam3::NUM32(d) => write!(f, "{}", d),
-            InstructionParam3::OFFSET(d) => write!(f, "{}", d.abs()),
-            InstructionParam3::FLOAT(d) => write!(f, "{}", d),
+            InstructionParam3::OFFSET(d) => write!(f, "{}", d),
+            // keep the fraction so that floats don't read back as integers
+            InstructionParam3::FLOAT(d) => write!(f, "{:?}", d),
             InstructionParam3::GVAR(d) => write!(f, "gvar_{}", d),
             InstructionParam3::LVAR(d) => write!(f, "lvar_{}", d),
             InstructionParam3::RAW(d) => write!(f, "{:02X}", d),
@@ -68,7 +106,10 @@ impl fmt::Display for InstructionParam3 {
 impl InstructionParam for InstructionParam3 {
     fn to_string(&self) -> Option<String> {
         match self {
-            InstructionParam3::STR(d) => Some(String::from(d)),
+            InstructionParam3::STR8(d) => {
+                let end = d.iter().position(|&b| b == 0).unwrap_or(d.len());
+                Some(String::from_utf8_lossy(&d[..end]).to_string())
+            }
             _ => None,
         }
     }
@@ -78,10 +119,36 @@ impl InstructionParam for InstructionParam3 {
             _ => None,
         }
     }
+    fn to_int(&self) -> Option<i32> {
+        match self {
+            InstructionParam3::NUM32(d) => Some(*d),
+            _ => None,
+        }
+    }
+    fn to_global(&self) -> Option<u16> {
+        match self {
+            InstructionParam3::GVAR(d) => Some(*d),
+            _ => None,
+        }
+    }
+    fn value(&self) -> ParamValue {
+        match self {
+            InstructionParam3::EOL => ParamValue::EOL,
+            InstructionParam3::RAW(d) => ParamValue::Raw(*d),
+            InstructionParam3::NUM32(d) => ParamValue::Int(*d),
+            InstructionParam3::FLOAT(d) => ParamValue::Float(*d),
+            InstructionParam3::STR8(_) => {
+                ParamValue::String(InstructionParam::to_string(self).unwrap_or_default())
+            }
+            InstructionParam3::GVAR(d) => ParamValue::Var(Variable::Global(*d), VarType::Any),
+            InstructionParam3::LVAR(d) => ParamValue::Var(Variable::Local(*d), VarType::Any),
+            InstructionParam3::OFFSET(d) => ParamValue::Offset(*d),
+        }
+    }
 }
 
 impl<'a> Iterator for Parser3<'a> {
-    type Item = Box<Instruction>;
+    type Item = Instruction;
 
     fn next(&mut self) -> Option<Self::Item> {
         let offset = self.0.get_position();
@@ -90,10 +157,13 @@ impl<'a> Iterator for Parser3<'a> {
             return None;
         }
 
-        Some(Box::new(
-            self.try_next(offset)
-                .unwrap_or_else(|_| self.rollback(offset).unwrap()),
-        ))
+        // an undecodable instruction is kept as a single raw byte
+        self.try_next(offset)
+            .or_else(|e| {
+                self.0.fail(&e);
+                self.rollback(offset)
+            })
+            .ok()
     }
 }
 
@@ -109,12 +179,14 @@ impl<'a> Parser3<'a> {
         Ok(Instruction {
             opcode: 0xFFFF,
             name: String::from(INVALID_OPCODE),
-            offset,
+            negated: false,
+            size: 1,
+            offset: offset + self.0.base_offset,
             params: vec![Box::new(self.get_raw()?)],
         })
     }
 
-    fn to_param(
+    fn read_param(
         &mut self,
         data_type: DataType3,
         param_type: &CommandParamType,
@@ -142,14 +214,9 @@ impl<'a> Parser3<'a> {
                 self.0.cursor.read_u16::<LittleEndian>()?,
             ))),
             DataType3::STR8 => {
-                let mut buf = vec![0; 8];
-                self.0.cursor.read_exact(buf.as_mut_slice())?;
-                unsafe {
-                    let s = str::from_utf8_unchecked(buf.as_mut_slice());
-                    Ok(Box::new(InstructionParam3::STR(
-                        s.split(char::from(0)).next().unwrap().to_string(),
-                    )))
-                }
+                let mut buf = [0; 8];
+                self.0.cursor.read_exact(&mut buf)?;
+                Ok(Box::new(InstructionParam3::STR8(buf)))
             }
             DataType3::FLOAT => Ok(Box::new(InstructionParam3::FLOAT(
                 f32::from(self.0.cursor.read_i16::<LittleEndian>()?) / 16.0,
@@ -158,26 +225,23 @@ impl<'a> Parser3<'a> {
     }
 
     pub fn try_next(&mut self, offset: u32) -> Result<Instruction, io::Error> {
+        self.0.begin(offset);
         let opcode = self.0.cursor.read_u16::<LittleEndian>()?;
-        let def = self.0.definitions.get(&(opcode & 0x7FFF)).ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Unknown opcode {} at {}", opcode, offset),
-            )
-        })?;
-        let mut params = vec![];
-
-        'outer: for param in def.input.iter().chain(def.output.iter()) {
+        self.0.attempt.opcode = Some(opcode);
+        let def = self
+            .0
+            .definitions
+            .get(&(opcode & 0x7FFF))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unknown opcode"))?;
+        let mut params: Vec<Box<dyn InstructionParam>> = vec![];
+
+        'outer: for (index, param) in def.input.iter().chain(def.output.iter()).enumerate() {
+            self.0.attempt.param = Some((index, param.r#type.clone()));
             loop {
-                let offset = self.0.get_position();
                 let next_byte = self.0.cursor.read_u8()?;
+                self.0.attempt.data_type = Some(next_byte);
 
-                let data_type = DataType3::try_from(next_byte).map_err(|_| {
-                    io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("Unknown data type {} at {}", next_byte, offset),
-                    )
-                })?;
+                let data_type = DataType3::from(next_byte);
 
                 if data_type == DataType3::STR8 {
                     self.0.set_position(self.0.get_position() - 1);
@@ -187,13 +251,16 @@ impl<'a> Parser3<'a> {
                     if param.r#type != CommandParamType::Arguments {
                         return Err(io::Error::new(
                             io::ErrorKind::InvalidData,
-                            format!("Unexpected EOL parameter at {}", offset),
+                            "Unexpected EOL parameter",
                         ));
                     }
                     break 'outer;
                 }
 
-                params.push(self.to_param(data_type, &param.r#type)?);
+                params.push(Box::new(TaggedParam {
+                    data_type: data_type.name(),
+                    param: self.read_param(data_type, &param.r#type)?,
+                }));
 
                 if param.r#type != CommandParamType::Arguments {
                     break;
@@ -204,6 +271,8 @@ impl<'a> Parser3<'a> {
         Ok(Instruction {
             opcode,
             name: def.name.clone(),
+            negated: opcode & 0x8000 != 0 && def.attrs.is_condition,
+            size: self.0.get_position() - offset,
             offset: offset + self.0.base_offset,
             params,
         })
@@ -214,9 +283,7 @@ impl<'a> Parser3<'a> {
         definitions: &'a HashMap<types::Opcode, Command>,
         base_offset: u32,
     ) -> Self {
-        Self {
-            0: parser::Parser::new(chunk, definitions, base_offset),
-        }
+        Self(parser::Parser::new(chunk, definitions, base_offset))
     }
 }
 
@@ -227,4 +294,11 @@ impl<'a> parser::Parse<'a> for Parser3<'a> {
     fn get_parser_as_mut(&mut self) -> &mut parser::Parser<'a> {
         &mut self.0
     }
+    fn decode(&mut self, position: u32) -> Result<Instruction, io::Error> {
+        self.0.set_position(position);
+        self.try_next(position).inspect_err(|e| self.0.fail(e))
+    }
+    fn decode_raw(&mut self, position: u32) -> Result<Instruction, io::Error> {
+        self.rollback(position)
+    }
 }
@@ -0,0 +1,396 @@
+use crate::library::Command;
+use crate::library::CommandParamType;
+use crate::parser;
+use crate::types;
+use crate::types::*;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::{fmt, io};
+
+/// First type tag of a local variable, the tag itself carries the variable index
+pub const LVAR_TAG: u8 = 0x0C;
+/// First type tag of a local array, followed by the index variable and the array size
+pub const LARRAY_TAG: u8 = 0x6C;
+/// First type tag of a global variable, the tag carries the high byte of the index
+pub const GVAR_TAG: u8 = 0xCC;
+
+#[derive(Debug, Clone)]
+pub enum InstructionParamLCS {
+    EOL,
+    RAW(u8),
+    NUM32(i32),
+    FLOAT(f32),
+    STR(String),
+    GVAR(u16),
+    LVAR(u16),
+    LARRAY(u16, u16, u8),
+    OFFSET(i32),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DataTypeLCS {
+    EOL,
+    NUM0,
+    FLOAT0,
+    NUM8,
+    NUM16,
+    NUM32,
+    FLOAT8,
+    FLOAT16,
+    FLOAT24,
+    FLOAT,
+    STR,
+    LVAR(u16),
+    LARRAY(u16),
+    GVAR(u8),
+}
+
+impl DataTypeLCS {
+    /// Decodes the type tags shared by both Stories games
+    pub fn from_var_tag(data_type: u8) -> Result<Self, u8> {
+        match data_type {
+            x if x >= GVAR_TAG => Ok(DataTypeLCS::GVAR(x - GVAR_TAG)),
+            x if x >= LARRAY_TAG => Ok(DataTypeLCS::LARRAY((x - LARRAY_TAG) as u16)),
+            x if x >= LVAR_TAG => Ok(DataTypeLCS::LVAR((x - LVAR_TAG) as u16)),
+            x => Err(x),
+        }
+    }
+
+    /// Name of the tag, the variable tags without the index they carry
+    pub fn name(&self) -> &'static str {
+        match self {
+            DataTypeLCS::EOL => "EOL",
+            DataTypeLCS::NUM0 => "NUM0",
+            DataTypeLCS::FLOAT0 => "FLOAT0",
+            DataTypeLCS::NUM8 => "NUM8",
+            DataTypeLCS::NUM16 => "NUM16",
+            DataTypeLCS::NUM32 => "NUM32",
+            DataTypeLCS::FLOAT8 => "FLOAT8",
+            DataTypeLCS::FLOAT16 => "FLOAT16",
+            DataTypeLCS::FLOAT24 => "FLOAT24",
+            DataTypeLCS::FLOAT => "FLOAT",
+            DataTypeLCS::STR => "STR",
+            DataTypeLCS::LVAR(_) => "LVAR",
+            DataTypeLCS::LARRAY(_) => "LARRAY",
+            DataTypeLCS::GVAR(_) => "GVAR",
+        }
+    }
+}
+
+/// LCS has no packed floats, the tags 0x07-0x09 that VCS uses for them are errors here.
+/// 0x0B is not assigned in either game: strings end at 0x0A and local variables start at 0x0C
+impl TryFrom<u8> for DataTypeLCS {
+    type Error = u8;
+    fn try_from(data_type: u8) -> Result<Self, Self::Error> {
+        match data_type {
+            0x00 => Ok(DataTypeLCS::EOL),
+            0x01 => Ok(DataTypeLCS::NUM0),
+            0x02 => Ok(DataTypeLCS::FLOAT0),
+            0x03 => Ok(DataTypeLCS::NUM8),
+            0x04 => Ok(DataTypeLCS::NUM16),
+            0x05 => Ok(DataTypeLCS::NUM32),
+            0x06 => Ok(DataTypeLCS::FLOAT),
+            0x0A => Ok(DataTypeLCS::STR),
+            x => DataTypeLCS::from_var_tag(x),
+        }
+    }
+}
+
+impl fmt::Display for InstructionParamLCS {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstructionParamLCS::EOL => write!(f, ""),
+            InstructionParamLCS::STR(d) => write!(f, "\"{}\"", d),
+            InstructionParamLCS::NUM32(d) => write!(f, "{}", d),
+            InstructionParamLCS::OFFSET(d) => write!(f, "{}", d),
+            InstructionParamLCS::FLOAT(d) => write!(f, "{}", d),
+            InstructionParamLCS::GVAR(d) => write!(f, "gvar_{}", d),
+            InstructionParamLCS::LVAR(d) => write!(f, "lvar_{}", d),
+            InstructionParamLCS::LARRAY(d, index, size) => {
+                write!(f, "lvar_{}(lvar_{},{})", d, index, size)
+            }
+            InstructionParamLCS::RAW(d) => write!(f, "{:02X}", d),
+        }
+    }
+}
+
+impl InstructionParam for InstructionParamLCS {
+    fn to_string(&self) -> Option<String> {
+        match self {
+            InstructionParamLCS::STR(d) => Some(String::from(d)),
+            _ => None,
+        }
+    }
+    fn to_offset(&self) -> Option<i32> {
+        match self {
+            InstructionParamLCS::OFFSET(d) => Some(*d),
+            _ => None,
+        }
+    }
+    fn to_int(&self) -> Option<i32> {
+        match self {
+            InstructionParamLCS::NUM32(d) => Some(*d),
+            _ => None,
+        }
+    }
+    fn to_global(&self) -> Option<u16> {
+        match self {
+            InstructionParamLCS::GVAR(d) => Some(*d),
+            _ => None,
+        }
+    }
+    fn value(&self) -> ParamValue {
+        match self {
+            InstructionParamLCS::EOL => ParamValue::EOL,
+            InstructionParamLCS::RAW(d) => ParamValue::Raw(*d),
+            InstructionParamLCS::NUM32(d) => ParamValue::Int(*d),
+            InstructionParamLCS::FLOAT(d) => ParamValue::Float(*d),
+            InstructionParamLCS::STR(d) => ParamValue::String(d.clone()),
+            // Stories games address globals by index
+            InstructionParamLCS::GVAR(d) => {
+                ParamValue::Var(Variable::Global(d.wrapping_mul(4)), VarType::Any)
+            }
+            InstructionParamLCS::LVAR(d) => ParamValue::Var(Variable::Local(*d), VarType::Any),
+            InstructionParamLCS::LARRAY(d, index, size) => ParamValue::Array {
+                var: Variable::Local(*d),
+                index: Variable::Local(*index),
+                size: *size,
+                element_type: VarType::Any,
+            },
+            InstructionParamLCS::OFFSET(d) => ParamValue::Offset(*d),
+        }
+    }
+}
+
+impl<'a> Iterator for ParserLCS<'a> {
+    type Item = Instruction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.0.get_position();
+
+        if self.0.size == offset {
+            return None;
+        }
+
+        self.try_next(offset)
+            .or_else(|e| {
+                self.0.fail(&e);
+                self.rollback(offset)
+            })
+            .ok()
+    }
+}
+
+pub struct ParserLCS<'a>(pub parser::Parser<'a>);
+
+impl<'a> ParserLCS<'a> {
+    pub fn get_raw(&mut self) -> Result<InstructionParamLCS, io::Error> {
+        Ok(InstructionParamLCS::RAW(self.0.cursor.read_u8()?))
+    }
+
+    pub fn rollback(&mut self, offset: u32) -> Result<Instruction, io::Error> {
+        self.0.set_position(offset);
+        Ok(Instruction {
+            opcode: 0xFFFF,
+            name: String::from(INVALID_OPCODE),
+            negated: false,
+            size: 1,
+            offset: offset + self.0.base_offset,
+            params: vec![Box::new(self.get_raw()?)],
+        })
+    }
+
+    pub fn read_param(
+        &mut self,
+        data_type: DataTypeLCS,
+        param_type: &CommandParamType,
+    ) -> Result<Box<dyn InstructionParam>, io::Error> {
+        let cursor = &mut self.0.cursor;
+        let param = match data_type {
+            DataTypeLCS::EOL => InstructionParamLCS::EOL,
+            DataTypeLCS::NUM0 => InstructionParamLCS::NUM32(0),
+            DataTypeLCS::NUM8 => InstructionParamLCS::NUM32(cursor.read_i8()? as _),
+            DataTypeLCS::NUM16 => {
+                InstructionParamLCS::NUM32(cursor.read_i16::<LittleEndian>()? as _)
+            }
+            DataTypeLCS::NUM32 => InstructionParamLCS::NUM32(cursor.read_i32::<LittleEndian>()?),
+            DataTypeLCS::FLOAT0 => InstructionParamLCS::FLOAT(0.0),
+            // packed floats keep only the most significant bytes of the value
+            DataTypeLCS::FLOAT8 => {
+                InstructionParamLCS::FLOAT(f32::from_bits((cursor.read_u8()? as u32) << 24))
+            }
+            DataTypeLCS::FLOAT16 => InstructionParamLCS::FLOAT(f32::from_bits(
+                (cursor.read_u16::<LittleEndian>()? as u32) << 16,
+            )),
+            DataTypeLCS::FLOAT24 => {
+                InstructionParamLCS::FLOAT(f32::from_bits(cursor.read_u24::<LittleEndian>()? << 8))
+            }
+            DataTypeLCS::FLOAT => InstructionParamLCS::FLOAT(cursor.read_f32::<LittleEndian>()?),
+            DataTypeLCS::STR => {
+                let mut buf = vec![];
+                loop {
+                    match cursor.read_u8()? {
+                        0 => break,
+                        c => buf.push(c),
+                    }
+                }
+                InstructionParamLCS::STR(String::from_utf8_lossy(&buf).to_string())
+            }
+            DataTypeLCS::LVAR(index) => InstructionParamLCS::LVAR(index),
+            DataTypeLCS::LARRAY(index) => {
+                InstructionParamLCS::LARRAY(index, cursor.read_u8()? as _, cursor.read_u8()?)
+            }
+            DataTypeLCS::GVAR(high) => {
+                InstructionParamLCS::GVAR((high as u16) << 8 | cursor.read_u8()? as u16)
+            }
+        };
+
+        // labels are compacted like any other integer
+        match param {
+            InstructionParamLCS::NUM32(val) if param_type == &CommandParamType::Label => {
+                Ok(Box::new(InstructionParamLCS::OFFSET(val)))
+            }
+            _ => Ok(Box::new(param)),
+        }
+    }
+
+    pub fn try_next(&mut self, offset: u32) -> Result<Instruction, io::Error> {
+        self.try_next_with(offset, DataTypeLCS::try_from)
+    }
+
+    /// Decodes the instruction with the given table of type tags, VCS assigns them differently
+    pub(super) fn try_next_with(
+        &mut self,
+        offset: u32,
+        get_data_type: fn(u8) -> Result<DataTypeLCS, u8>,
+    ) -> Result<Instruction, io::Error> {
+        self.0.begin(offset);
+        let opcode = self.0.cursor.read_u16::<LittleEndian>()?;
+        self.0.attempt.opcode = Some(opcode);
+        let def = self
+            .0
+            .definitions
+            .get(&(opcode & 0x7FFF))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unknown opcode"))?;
+        let mut params: Vec<Box<dyn InstructionParam>> = vec![];
+
+        'outer: for (index, param) in def.input.iter().chain(def.output.iter()).enumerate() {
+            self.0.attempt.param = Some((index, param.r#type.clone()));
+            loop {
+                let next_byte = self.0.cursor.read_u8()?;
+                self.0.attempt.data_type = Some(next_byte);
+
+                let data_type = get_data_type(next_byte)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Unknown data type"))?;
+
+                if data_type == DataTypeLCS::EOL {
+                    if param.r#type != CommandParamType::Arguments {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Unexpected EOL parameter",
+                        ));
+                    }
+                    break 'outer;
+                }
+
+                params.push(Box::new(TaggedParam {
+                    data_type: data_type.name(),
+                    param: self.read_param(data_type, &param.r#type)?,
+                }));
+
+                if param.r#type != CommandParamType::Arguments {
+                    break;
+                }
+            }
+        }
+
+        Ok(Instruction {
+            opcode,
+            name: def.name.clone(),
+            negated: opcode & 0x8000 != 0 && def.attrs.is_condition,
+            size: self.0.get_position() - offset,
+            offset: offset + self.0.base_offset,
+            params,
+        })
+    }
+
+    pub fn new(
+        chunk: &'a ScriptChunk,
+        definitions: &'a HashMap<types::Opcode, Command>,
+        base_offset: u32,
+    ) -> Self {
+        Self(parser::Parser::new(chunk, definitions, base_offset))
+    }
+}
+
+impl<'a> parser::Parse<'a> for ParserLCS<'a> {
+    fn get_parser(&self) -> &parser::Parser<'a> {
+        &self.0
+    }
+    fn get_parser_as_mut(&mut self) -> &mut parser::Parser<'a> {
+        &mut self.0
+    }
+    fn decode(&mut self, position: u32) -> Result<Instruction, io::Error> {
+        self.0.set_position(position);
+        self.try_next(position).inspect_err(|e| self.0.fail(e))
+    }
+    fn decode_raw(&mut self, position: u32) -> Result<Instruction, io::Error> {
+        self.rollback(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::platform::test_support::decode;
+    use crate::platform::Game;
+    use crate::types::INVALID_OPCODE;
+
+    #[test]
+    fn decodes_type_tags() {
+        #[rustfmt::skip]
+        let params = [
+            0x01,
+            0x02,
+            0x03, 0xFF,
+            0x04, 0x00, 0x01,
+            0x05, 0x78, 0x56, 0x34, 0x12,
+            0x06, 0x00, 0x00, 0x80, 0x3F,
+            0x0A, b'A', b'B', 0x00,
+            0x0C,
+            0x6B,
+            0x6C, 0x01, 0x08,
+            0xCC, 0x05,
+            0xCD, 0x05,
+            0x00,
+        ];
+        let (name, params) = decode(&Game::LCS, &params);
+        assert_eq!(name, "TEST");
+        assert_eq!(
+            params,
+            [
+                "0",
+                "0",
+                "-1",
+                "256",
+                "305419896",
+                "1",
+                "\"AB\"",
+                "lvar_0",
+                "lvar_95",
+                "lvar_0(lvar_1,8)",
+                "gvar_5",
+                "gvar_261"
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unassigned_type_tags() {
+        for tag in [0x07, 0x08, 0x09, 0x0B] {
+            let (name, _) = decode(&Game::LCS, &[tag, 0x00, 0x00, 0x00, 0x00, 0x00]);
+            assert_eq!(name, INVALID_OPCODE, "tag {:02X}", tag);
+        }
+    }
+}
@@ -6,12 +6,21 @@ use crate::library::Command;
 use crate::parser;
 use crate::types;
 pub mod gta3;
+pub mod lcs;
+pub mod sa;
 pub mod vc;
+pub mod vcs;
+
+#[cfg(test)]
+mod test_support;
 
 #[derive(Debug, Clone, ValueEnum)]
 pub enum Game {
     GTA3,
-    VC
+    VC,
+    SA,
+    LCS,
+    VCS,
 }
 
 pub fn get_parser<'a>(
@@ -23,5 +32,8 @@ pub fn get_parser<'a>(
     match game {
         Game::GTA3 => Box::new(gta3::Parser3::new(chunk, definitions, base_offset)),
         Game::VC => Box::new(vc::ParserVC::new(chunk, definitions, base_offset)),
+        Game::SA => Box::new(sa::ParserSA::new(chunk, definitions, base_offset)),
+        Game::LCS => Box::new(lcs::ParserLCS::new(chunk, definitions, base_offset)),
+        Game::VCS => Box::new(vcs::ParserVCS::new(chunk, definitions, base_offset)),
     }
 }
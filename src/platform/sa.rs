@@ -0,0 +1,541 @@
+use crate::library::Command;
+use crate::library::CommandParamType;
+use crate::parser;
+use crate::platform::gta3::escape_str;
+use crate::types;
+use crate::types::*;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Read;
+use std::{fmt, io};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VarTypeSA {
+    NUM,
+    INT,
+    FLOAT,
+    STR8,
+    STR16,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArraySA {
+    pub offset: u16,
+    pub index: u16,
+    pub size: u8,
+    pub element_type: VarTypeSA,
+    pub is_global_index: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum InstructionParamSA {
+    EOL,
+    RAW(u8),
+    NUM32(i32),
+    FLOAT(f32),
+    STR8(Vec<u8>),
+    STR16(Vec<u8>),
+    VARLEN(Vec<u8>),
+    GVAR(u16, VarTypeSA),
+    LVAR(u16, VarTypeSA),
+    GARRAY(ArraySA),
+    LARRAY(ArraySA),
+    OFFSET(i32),
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, PartialEq, Eq)]
+pub enum DataTypeSA {
+    EOL,
+    NUM8,
+    NUM16,
+    NUM32,
+    FLOAT,
+    GVAR,
+    LVAR,
+    GARRAY,
+    LARRAY,
+    STR8,
+    GVAR_STR8,
+    LVAR_STR8,
+    GARRAY_STR8,
+    LARRAY_STR8,
+    VARLEN,
+    STR16,
+    GVAR_STR16,
+    LVAR_STR16,
+    GARRAY_STR16,
+    LARRAY_STR16,
+}
+
+impl DataTypeSA {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DataTypeSA::EOL => "EOL",
+            DataTypeSA::NUM8 => "NUM8",
+            DataTypeSA::NUM16 => "NUM16",
+            DataTypeSA::NUM32 => "NUM32",
+            DataTypeSA::FLOAT => "FLOAT",
+            DataTypeSA::GVAR => "GVAR",
+            DataTypeSA::LVAR => "LVAR",
+            DataTypeSA::GARRAY => "GARRAY",
+            DataTypeSA::LARRAY => "LARRAY",
+            DataTypeSA::STR8 => "STR8",
+            DataTypeSA::GVAR_STR8 => "GVAR_STR8",
+            DataTypeSA::LVAR_STR8 => "LVAR_STR8",
+            DataTypeSA::GARRAY_STR8 => "GARRAY_STR8",
+            DataTypeSA::LARRAY_STR8 => "LARRAY_STR8",
+            DataTypeSA::VARLEN => "VARLEN",
+            DataTypeSA::STR16 => "STR16",
+            DataTypeSA::GVAR_STR16 => "GVAR_STR16",
+            DataTypeSA::LVAR_STR16 => "LVAR_STR16",
+            DataTypeSA::GARRAY_STR16 => "GARRAY_STR16",
+            DataTypeSA::LARRAY_STR16 => "LARRAY_STR16",
+        }
+    }
+}
+
+impl TryFrom<u8> for DataTypeSA {
+    type Error = u8;
+    fn try_from(data_type: u8) -> Result<Self, Self::Error> {
+        match data_type {
+            0x00 => Ok(DataTypeSA::EOL),
+            0x01 => Ok(DataTypeSA::NUM32),
+            0x02 => Ok(DataTypeSA::GVAR),
+            0x03 => Ok(DataTypeSA::LVAR),
+            0x04 => Ok(DataTypeSA::NUM8),
+            0x05 => Ok(DataTypeSA::NUM16),
+            0x06 => Ok(DataTypeSA::FLOAT),
+            0x07 => Ok(DataTypeSA::GARRAY),
+            0x08 => Ok(DataTypeSA::LARRAY),
+            0x09 => Ok(DataTypeSA::STR8),
+            0x0A => Ok(DataTypeSA::GVAR_STR8),
+            0x0B => Ok(DataTypeSA::LVAR_STR8),
+            0x0C => Ok(DataTypeSA::GARRAY_STR8),
+            0x0D => Ok(DataTypeSA::LARRAY_STR8),
+            0x0E => Ok(DataTypeSA::VARLEN),
+            0x0F => Ok(DataTypeSA::STR16),
+            0x10 => Ok(DataTypeSA::GVAR_STR16),
+            0x11 => Ok(DataTypeSA::LVAR_STR16),
+            0x12 => Ok(DataTypeSA::GARRAY_STR16),
+            0x13 => Ok(DataTypeSA::LARRAY_STR16),
+            x => Err(x),
+        }
+    }
+}
+
+impl fmt::Display for VarTypeSA {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VarTypeSA::NUM => write!(f, ""),
+            VarTypeSA::INT => write!(f, "i"),
+            VarTypeSA::FLOAT => write!(f, "f"),
+            VarTypeSA::STR8 => write!(f, "s"),
+            VarTypeSA::STR16 => write!(f, "v"),
+        }
+    }
+}
+
+impl From<&VarTypeSA> for VarType {
+    fn from(var_type: &VarTypeSA) -> Self {
+        match var_type {
+            VarTypeSA::NUM => VarType::Any,
+            VarTypeSA::INT => VarType::Int,
+            VarTypeSA::FLOAT => VarType::Float,
+            VarTypeSA::STR8 => VarType::ShortString,
+            VarTypeSA::STR16 => VarType::LongString,
+        }
+    }
+}
+
+impl ArraySA {
+    fn to_value(&self, var: Variable) -> ParamValue {
+        ParamValue::Array {
+            var,
+            index: if self.is_global_index {
+                Variable::Global(self.index)
+            } else {
+                Variable::Local(self.index)
+            },
+            size: self.size,
+            element_type: (&self.element_type).into(),
+        }
+    }
+}
+
+impl fmt::Display for ArraySA {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scope = if self.is_global_index { "gvar" } else { "lvar" };
+        write!(
+            f,
+            "{}({}_{},{}{})",
+            self.offset, scope, self.index, self.size, self.element_type
+        )
+    }
+}
+
+impl fmt::Display for InstructionParamSA {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstructionParamSA::EOL => write!(f, ""),
+            InstructionParamSA::STR8(d)
+            | InstructionParamSA::STR16(d)
+            | InstructionParamSA::VARLEN(d) => write!(f, "{}", escape_str(d)),
+            InstructionParamSA::NUM32(d) => write!(f, "{}", d),
+            InstructionParamSA::OFFSET(d) => write!(f, "{}", d),
+            // keep the fraction so that floats don't read back as integers
+            InstructionParamSA::FLOAT(d) => write!(f, "{:?}", d),
+            InstructionParamSA::GVAR(d, t) => write!(f, "{}gvar_{}", t, d),
+            InstructionParamSA::LVAR(d, t) => write!(f, "{}lvar_{}", t, d),
+            InstructionParamSA::GARRAY(d) => write!(f, "gvar_{}", d),
+            InstructionParamSA::LARRAY(d) => write!(f, "lvar_{}", d),
+            InstructionParamSA::RAW(d) => write!(f, "{:02X}", d),
+        }
+    }
+}
+
+impl InstructionParam for InstructionParamSA {
+    fn to_string(&self) -> Option<String> {
+        match self {
+            InstructionParamSA::STR8(d)
+            | InstructionParamSA::STR16(d)
+            | InstructionParamSA::VARLEN(d) => {
+                let end = d.iter().position(|&b| b == 0).unwrap_or(d.len());
+                Some(String::from_utf8_lossy(&d[..end]).to_string())
+            }
+            _ => None,
+        }
+    }
+    fn to_offset(&self) -> Option<i32> {
+        match self {
+            InstructionParamSA::OFFSET(d) => Some(*d),
+            _ => None,
+        }
+    }
+    fn to_int(&self) -> Option<i32> {
+        match self {
+            InstructionParamSA::NUM32(d) => Some(*d),
+            _ => None,
+        }
+    }
+    fn to_global(&self) -> Option<u16> {
+        match self {
+            InstructionParamSA::GVAR(d, _) => Some(*d),
+            InstructionParamSA::GARRAY(d) => Some(d.offset),
+            _ => None,
+        }
+    }
+    fn value(&self) -> ParamValue {
+        match self {
+            InstructionParamSA::EOL => ParamValue::EOL,
+            InstructionParamSA::RAW(d) => ParamValue::Raw(*d),
+            InstructionParamSA::NUM32(d) => ParamValue::Int(*d),
+            InstructionParamSA::FLOAT(d) => ParamValue::Float(*d),
+            InstructionParamSA::STR8(_) => {
+                ParamValue::String(InstructionParam::to_string(self).unwrap_or_default())
+            }
+            InstructionParamSA::STR16(_) | InstructionParamSA::VARLEN(_) => {
+                ParamValue::LongString(InstructionParam::to_string(self).unwrap_or_default())
+            }
+            InstructionParamSA::GVAR(d, t) => ParamValue::Var(Variable::Global(*d), t.into()),
+            InstructionParamSA::LVAR(d, t) => ParamValue::Var(Variable::Local(*d), t.into()),
+            InstructionParamSA::GARRAY(d) => d.to_value(Variable::Global(d.offset)),
+            InstructionParamSA::LARRAY(d) => d.to_value(Variable::Local(d.offset)),
+            InstructionParamSA::OFFSET(d) => ParamValue::Offset(*d),
+        }
+    }
+}
+
+impl<'a> Iterator for ParserSA<'a> {
+    type Item = Instruction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.0.get_position();
+
+        if self.0.size == offset {
+            return None;
+        }
+
+        self.try_next(offset)
+            .or_else(|e| {
+                self.0.fail(&e);
+                self.rollback(offset)
+            })
+            .ok()
+    }
+}
+
+pub struct ParserSA<'a>(pub parser::Parser<'a>);
+
+impl<'a> ParserSA<'a> {
+    pub fn get_raw(&mut self) -> Result<InstructionParamSA, io::Error> {
+        Ok(InstructionParamSA::RAW(self.0.cursor.read_u8()?))
+    }
+
+    pub fn rollback(&mut self, offset: u32) -> Result<Instruction, io::Error> {
+        self.0.set_position(offset);
+        Ok(Instruction {
+            opcode: 0xFFFF,
+            name: String::from(INVALID_OPCODE),
+            negated: false,
+            size: 1,
+            offset: offset + self.0.base_offset,
+            params: vec![Box::new(self.get_raw()?)],
+        })
+    }
+
+    fn read_string(&mut self, len: usize) -> Result<Vec<u8>, io::Error> {
+        let mut buf = vec![0; len];
+        self.0.cursor.read_exact(buf.as_mut_slice())?;
+        Ok(buf)
+    }
+
+    fn read_array(&mut self, element_type: Option<VarTypeSA>) -> Result<ArraySA, io::Error> {
+        let offset = self.0.cursor.read_u16::<LittleEndian>()?;
+        let index = self.0.cursor.read_u16::<LittleEndian>()?;
+        let size = self.0.cursor.read_u8()?;
+        let props = self.0.cursor.read_u8()?;
+        let element_type = element_type.unwrap_or(match props & 0x7F {
+            1 => VarTypeSA::FLOAT,
+            2 => VarTypeSA::STR8,
+            3 => VarTypeSA::STR16,
+            _ => VarTypeSA::INT,
+        });
+        Ok(ArraySA {
+            offset,
+            index,
+            size,
+            element_type,
+            is_global_index: props & 0x80 != 0,
+        })
+    }
+
+    fn read_param(
+        &mut self,
+        data_type: DataTypeSA,
+        param_type: &CommandParamType,
+    ) -> Result<Box<dyn InstructionParam>, io::Error> {
+        let param = match data_type {
+            DataTypeSA::EOL => InstructionParamSA::EOL,
+            DataTypeSA::NUM8 => InstructionParamSA::NUM32(self.0.cursor.read_i8()? as _),
+            DataTypeSA::NUM16 => {
+                InstructionParamSA::NUM32(self.0.cursor.read_i16::<LittleEndian>()? as _)
+            }
+            DataTypeSA::NUM32 => {
+                let val = self.0.cursor.read_i32::<LittleEndian>()?;
+                if param_type == &CommandParamType::Label {
+                    InstructionParamSA::OFFSET(val)
+                } else {
+                    InstructionParamSA::NUM32(val)
+                }
+            }
+            DataTypeSA::FLOAT => {
+                InstructionParamSA::FLOAT(self.0.cursor.read_f32::<LittleEndian>()?)
+            }
+            DataTypeSA::GVAR => {
+                InstructionParamSA::GVAR(self.0.cursor.read_u16::<LittleEndian>()?, VarTypeSA::NUM)
+            }
+            DataTypeSA::LVAR => {
+                InstructionParamSA::LVAR(self.0.cursor.read_u16::<LittleEndian>()?, VarTypeSA::NUM)
+            }
+            DataTypeSA::GVAR_STR8 => {
+                InstructionParamSA::GVAR(self.0.cursor.read_u16::<LittleEndian>()?, VarTypeSA::STR8)
+            }
+            DataTypeSA::LVAR_STR8 => {
+                InstructionParamSA::LVAR(self.0.cursor.read_u16::<LittleEndian>()?, VarTypeSA::STR8)
+            }
+            DataTypeSA::GVAR_STR16 => InstructionParamSA::GVAR(
+                self.0.cursor.read_u16::<LittleEndian>()?,
+                VarTypeSA::STR16,
+            ),
+            DataTypeSA::LVAR_STR16 => InstructionParamSA::LVAR(
+                self.0.cursor.read_u16::<LittleEndian>()?,
+                VarTypeSA::STR16,
+            ),
+            DataTypeSA::GARRAY => InstructionParamSA::GARRAY(self.read_array(None)?),
+            DataTypeSA::LARRAY => InstructionParamSA::LARRAY(self.read_array(None)?),
+            DataTypeSA::GARRAY_STR8 => {
+                InstructionParamSA::GARRAY(self.read_array(Some(VarTypeSA::STR8))?)
+            }
+            DataTypeSA::LARRAY_STR8 => {
+                InstructionParamSA::LARRAY(self.read_array(Some(VarTypeSA::STR8))?)
+            }
+            DataTypeSA::GARRAY_STR16 => {
+                InstructionParamSA::GARRAY(self.read_array(Some(VarTypeSA::STR16))?)
+            }
+            DataTypeSA::LARRAY_STR16 => {
+                InstructionParamSA::LARRAY(self.read_array(Some(VarTypeSA::STR16))?)
+            }
+            DataTypeSA::STR8 => InstructionParamSA::STR8(self.read_string(8)?),
+            DataTypeSA::STR16 => InstructionParamSA::STR16(self.read_string(16)?),
+            DataTypeSA::VARLEN => {
+                let len = self.0.cursor.read_u8()?;
+                InstructionParamSA::VARLEN(self.read_string(len as usize)?)
+            }
+        };
+        Ok(Box::new(param))
+    }
+
+    pub fn try_next(&mut self, offset: u32) -> Result<Instruction, io::Error> {
+        self.0.begin(offset);
+        let opcode = self.0.cursor.read_u16::<LittleEndian>()?;
+        self.0.attempt.opcode = Some(opcode);
+        let def = self
+            .0
+            .definitions
+            .get(&(opcode & 0x7FFF))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unknown opcode"))?;
+        let mut params: Vec<Box<dyn InstructionParam>> = vec![];
+
+        'outer: for (index, param) in def.input.iter().chain(def.output.iter()).enumerate() {
+            self.0.attempt.param = Some((index, param.r#type.clone()));
+            loop {
+                let next_byte = self.0.cursor.read_u8()?;
+                self.0.attempt.data_type = Some(next_byte);
+
+                let data_type = DataTypeSA::try_from(next_byte)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Unknown data type"))?;
+
+                if data_type == DataTypeSA::EOL {
+                    if param.r#type != CommandParamType::Arguments {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Unexpected EOL parameter",
+                        ));
+                    }
+                    break 'outer;
+                }
+
+                params.push(Box::new(TaggedParam {
+                    data_type: data_type.name(),
+                    param: self.read_param(data_type, &param.r#type)?,
+                }));
+
+                if param.r#type != CommandParamType::Arguments {
+                    break;
+                }
+            }
+        }
+
+        Ok(Instruction {
+            opcode,
+            name: def.name.clone(),
+            negated: opcode & 0x8000 != 0 && def.attrs.is_condition,
+            size: self.0.get_position() - offset,
+            offset: offset + self.0.base_offset,
+            params,
+        })
+    }
+
+    pub fn new(
+        chunk: &'a ScriptChunk,
+        definitions: &'a HashMap<types::Opcode, Command>,
+        base_offset: u32,
+    ) -> Self {
+        Self(parser::Parser::new(chunk, definitions, base_offset))
+    }
+}
+
+impl<'a> parser::Parse<'a> for ParserSA<'a> {
+    fn get_parser(&self) -> &parser::Parser<'a> {
+        &self.0
+    }
+    fn get_parser_as_mut(&mut self) -> &mut parser::Parser<'a> {
+        &mut self.0
+    }
+    fn decode(&mut self, position: u32) -> Result<Instruction, io::Error> {
+        self.0.set_position(position);
+        self.try_next(position).inspect_err(|e| self.0.fail(e))
+    }
+    fn decode_raw(&mut self, position: u32) -> Result<Instruction, io::Error> {
+        self.rollback(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::platform::test_support::decode;
+    use crate::platform::Game;
+    use crate::types::INVALID_OPCODE;
+
+    #[test]
+    fn decodes_type_tags() {
+        #[rustfmt::skip]
+        let params = [
+            0x01, 0x78, 0x56, 0x34, 0x12,
+            0x02, 0x08, 0x00,
+            0x03, 0x02, 0x00,
+            0x04, 0xFF,
+            0x05, 0x00, 0x01,
+            0x06, 0x00, 0x00, 0x80, 0x3F,
+            0x07, 0x10, 0x00, 0x03, 0x00, 0x05, 0x81,
+            0x08, 0x02, 0x00, 0x01, 0x00, 0x04, 0x00,
+            0x09, b'M', b'A', b'I', b'N', 0x00, 0xCC, 0xCC, 0xCC,
+            0x0A, 0x04, 0x00,
+            0x0B, 0x01, 0x00,
+            0x0C, 0x10, 0x00, 0x03, 0x00, 0x05, 0x81,
+            0x0D, 0x02, 0x00, 0x01, 0x00, 0x04, 0x00,
+            0x0E, 0x03, b'a', b'"', b'b',
+            0x0F, b'L', b'O', b'N', b'G', b' ', b'N', b'A', b'M', b'E', 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x10, 0x04, 0x00,
+            0x11, 0x01, 0x00,
+            0x12, 0x10, 0x00, 0x03, 0x00, 0x05, 0x80,
+            0x13, 0x02, 0x00, 0x01, 0x00, 0x04, 0x00,
+            0x00,
+        ];
+        let (name, params) = decode(&Game::SA, &params);
+        assert_eq!(name, "TEST");
+        assert_eq!(
+            params,
+            [
+                "305419896",
+                "gvar_8",
+                "lvar_2",
+                "-1",
+                "256",
+                "1.0",
+                "gvar_16(gvar_3,5f)",
+                "lvar_2(lvar_1,4i)",
+                "\"MAIN\"[CCCCCC]",
+                "sgvar_4",
+                "slvar_1",
+                // the tag sets the element type, only the index flag is read
+                "gvar_16(gvar_3,5s)",
+                "lvar_2(lvar_1,4s)",
+                "\"a\\\"b\"",
+                "\"LONG NAME\"",
+                "vgvar_4",
+                "vlvar_1",
+                "gvar_16(gvar_3,5v)",
+                "lvar_2(lvar_1,4v)",
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_array_flags() {
+        // bits 0-6 are the element type, bit 7 makes the index a global variable
+        for (flags, expected) in [
+            (0x00, "lvar_2(lvar_1,4i)"),
+            (0x01, "lvar_2(lvar_1,4f)"),
+            (0x02, "lvar_2(lvar_1,4s)"),
+            (0x03, "lvar_2(lvar_1,4v)"),
+            (0x80, "lvar_2(gvar_1,4i)"),
+            (0x83, "lvar_2(gvar_1,4v)"),
+        ] {
+            let (_, params) = decode(
+                &Game::SA,
+                &[0x08, 0x02, 0x00, 0x01, 0x00, 0x04, flags, 0x00],
+            );
+            assert_eq!(params, [expected], "flags {:02X}", flags);
+        }
+    }
+
+    #[test]
+    fn rejects_unassigned_type_tag() {
+        let (name, _) = decode(&Game::SA, &[0x14, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(name, INVALID_OPCODE);
+    }
+}
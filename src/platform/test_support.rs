@@ -0,0 +1,16 @@
+use crate::library::Command;
+use crate::platform::{get_parser, Game};
+use std::collections::HashMap;
+
+/// Decodes a command taking any number of arguments, the params follow its opcode
+pub fn decode(game: &Game, params: &[u8]) -> (String, Vec<String>) {
+    let command: Command = serde_json::from_str(
+        r#"{"id": "0001", "name": "TEST", "num_params": -1, "input": [{"name": "args", "type": "arguments"}]}"#,
+    )
+    .unwrap();
+    let defs = HashMap::from([(1, command)]);
+    let chunk = [&[0x01, 0x00], params].concat();
+    let inst = get_parser(game, &chunk, &defs, 0).next().unwrap();
+    let params = inst.params.iter().map(|p| format!("{}", p)).collect();
+    (inst.name, params)
+}
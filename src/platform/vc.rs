@@ -6,15 +6,14 @@ use crate::types::*;
 
 use byteorder::{LittleEndian, ReadBytesExt};
 use std::collections::HashMap;
-use std::convert::TryFrom;
+use std::io;
 use std::io::Read;
-use std::{io, str};
 
 use super::gta3::DataType3;
 use super::gta3::InstructionParam3;
 
 impl<'a> Iterator for ParserVC<'a> {
-    type Item = Box<Instruction>;
+    type Item = Instruction;
 
     fn next(&mut self) -> Option<Self::Item> {
         let offset = self.0.get_position();
@@ -23,10 +22,12 @@ impl<'a> Iterator for ParserVC<'a> {
             return None;
         }
 
-        Some(Box::new(
-            self.try_next(offset)
-                .unwrap_or_else(|_| self.rollback(offset).unwrap()),
-        ))
+        self.try_next(offset)
+            .or_else(|e| {
+                self.0.fail(&e);
+                self.rollback(offset)
+            })
+            .ok()
     }
 }
 
@@ -42,12 +43,14 @@ impl<'a> ParserVC<'a> {
         Ok(Instruction {
             opcode: 0xFFFF,
             name: String::from(INVALID_OPCODE),
-            offset,
+            negated: false,
+            size: 1,
+            offset: offset + self.0.base_offset,
             params: vec![Box::new(self.get_raw()?)],
         })
     }
 
-    fn to_param(
+    fn read_param(
         &mut self,
         data_type: DataType3,
         param_type: &CommandParamType,
@@ -75,42 +78,34 @@ impl<'a> ParserVC<'a> {
                 self.0.cursor.read_u16::<LittleEndian>()?,
             ))),
             DataType3::STR8 => {
-                let mut buf = vec![0; 8];
-                self.0.cursor.read_exact(buf.as_mut_slice())?;
-                unsafe {
-                    let s = str::from_utf8_unchecked(buf.as_mut_slice());
-                    Ok(Box::new(InstructionParam3::STR(
-                        s.split(char::from(0)).next().unwrap().to_string(),
-                    )))
-                }
+                let mut buf = [0; 8];
+                self.0.cursor.read_exact(&mut buf)?;
+                Ok(Box::new(InstructionParam3::STR8(buf)))
             }
-            DataType3::FLOAT => Ok(Box::new(InstructionParam3::FLOAT(f32::from(
+            DataType3::FLOAT => Ok(Box::new(InstructionParam3::FLOAT(
                 self.0.cursor.read_f32::<LittleEndian>()?,
-            )))),
+            ))),
         }
     }
 
     pub fn try_next(&mut self, offset: u32) -> Result<Instruction, io::Error> {
+        self.0.begin(offset);
         let opcode = self.0.cursor.read_u16::<LittleEndian>()?;
-        let def = self.0.definitions.get(&(opcode & 0x7FFF)).ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Unknown opcode {} at {}", opcode, offset),
-            )
-        })?;
-        let mut params = vec![];
-
-        'outer: for param in def.input.iter().chain(def.output.iter()) {
+        self.0.attempt.opcode = Some(opcode);
+        let def = self
+            .0
+            .definitions
+            .get(&(opcode & 0x7FFF))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unknown opcode"))?;
+        let mut params: Vec<Box<dyn InstructionParam>> = vec![];
+
+        'outer: for (index, param) in def.input.iter().chain(def.output.iter()).enumerate() {
+            self.0.attempt.param = Some((index, param.r#type.clone()));
             loop {
-                let offset = self.0.get_position();
                 let next_byte = self.0.cursor.read_u8()?;
+                self.0.attempt.data_type = Some(next_byte);
 
-                let data_type = DataType3::try_from(next_byte).map_err(|_| {
-                    io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("Unknown data type {} at {}", next_byte, offset),
-                    )
-                })?;
+                let data_type = DataType3::from(next_byte);
 
                 if data_type == DataType3::STR8 {
                     self.0.set_position(self.0.get_position() - 1);
@@ -120,13 +115,16 @@ impl<'a> ParserVC<'a> {
                     if param.r#type != CommandParamType::Arguments {
                         return Err(io::Error::new(
                             io::ErrorKind::InvalidData,
-                            format!("Unexpected EOL parameter at {}", offset),
+                            "Unexpected EOL parameter",
                         ));
                     }
                     break 'outer;
                 }
 
-                params.push(self.to_param(data_type, &param.r#type)?);
+                params.push(Box::new(TaggedParam {
+                    data_type: data_type.name(),
+                    param: self.read_param(data_type, &param.r#type)?,
+                }));
 
                 if param.r#type != CommandParamType::Arguments {
                     break;
@@ -137,6 +135,8 @@ impl<'a> ParserVC<'a> {
         Ok(Instruction {
             opcode,
             name: def.name.clone(),
+            negated: opcode & 0x8000 != 0 && def.attrs.is_condition,
+            size: self.0.get_position() - offset,
             offset: offset + self.0.base_offset,
             params,
         })
@@ -147,9 +147,7 @@ impl<'a> ParserVC<'a> {
         definitions: &'a HashMap<types::Opcode, Command>,
         base_offset: u32,
     ) -> Self {
-        Self {
-            0: parser::Parser::new(chunk, definitions, base_offset),
-        }
+        Self(parser::Parser::new(chunk, definitions, base_offset))
     }
 }
 
@@ -160,4 +158,11 @@ impl<'a> parser::Parse<'a> for ParserVC<'a> {
     fn get_parser_as_mut(&mut self) -> &mut parser::Parser<'a> {
         &mut self.0
     }
+    fn decode(&mut self, position: u32) -> Result<Instruction, io::Error> {
+        self.0.set_position(position);
+        self.try_next(position).inspect_err(|e| self.0.fail(e))
+    }
+    fn decode_raw(&mut self, position: u32) -> Result<Instruction, io::Error> {
+        self.rollback(position)
+    }
 }
@@ -0,0 +1,119 @@
+use crate::library::Command;
+use crate::parser;
+use crate::types;
+use crate::types::*;
+
+use std::collections::HashMap;
+use std::io;
+
+use super::lcs::DataTypeLCS;
+use super::lcs::ParserLCS;
+
+/// VCS packs floats into 1-3 bytes where the low bytes of the value are zero
+fn get_data_type(data_type: u8) -> Result<DataTypeLCS, u8> {
+    match data_type {
+        0x00 => Ok(DataTypeLCS::EOL),
+        0x01 => Ok(DataTypeLCS::NUM0),
+        0x02 => Ok(DataTypeLCS::FLOAT0),
+        0x03 => Ok(DataTypeLCS::NUM8),
+        0x04 => Ok(DataTypeLCS::NUM16),
+        0x05 => Ok(DataTypeLCS::NUM32),
+        0x06 => Ok(DataTypeLCS::FLOAT8),
+        0x07 => Ok(DataTypeLCS::FLOAT16),
+        0x08 => Ok(DataTypeLCS::FLOAT24),
+        0x09 => Ok(DataTypeLCS::FLOAT),
+        0x0A => Ok(DataTypeLCS::STR),
+        x => DataTypeLCS::from_var_tag(x),
+    }
+}
+
+impl<'a> Iterator for ParserVCS<'a> {
+    type Item = Instruction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.0 .0.get_position();
+
+        if self.0 .0.size == offset {
+            return None;
+        }
+
+        self.try_next(offset)
+            .or_else(|e| {
+                self.0 .0.fail(&e);
+                self.0.rollback(offset)
+            })
+            .ok()
+    }
+}
+
+pub struct ParserVCS<'a>(pub ParserLCS<'a>);
+
+impl<'a> ParserVCS<'a> {
+    pub fn try_next(&mut self, offset: u32) -> Result<Instruction, io::Error> {
+        self.0.try_next_with(offset, get_data_type)
+    }
+
+    pub fn new(
+        chunk: &'a ScriptChunk,
+        definitions: &'a HashMap<types::Opcode, Command>,
+        base_offset: u32,
+    ) -> Self {
+        Self(ParserLCS::new(chunk, definitions, base_offset))
+    }
+}
+
+impl<'a> parser::Parse<'a> for ParserVCS<'a> {
+    fn get_parser(&self) -> &parser::Parser<'a> {
+        &self.0 .0
+    }
+    fn get_parser_as_mut(&mut self) -> &mut parser::Parser<'a> {
+        &mut self.0 .0
+    }
+    fn decode(&mut self, position: u32) -> Result<Instruction, io::Error> {
+        self.0 .0.set_position(position);
+        self.try_next(position).inspect_err(|e| self.0 .0.fail(e))
+    }
+    fn decode_raw(&mut self, position: u32) -> Result<Instruction, io::Error> {
+        self.0.rollback(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::platform::test_support::decode;
+    use crate::platform::Game;
+    use crate::types::INVALID_OPCODE;
+
+    #[test]
+    fn decodes_packed_floats() {
+        #[rustfmt::skip]
+        let params = [
+            0x02,
+            0x06, 0x40,
+            0x07, 0x80, 0x3F,
+            0x08, 0x00, 0xC0, 0x3F,
+            0x09, 0x00, 0x00, 0x20, 0x41,
+            0x00,
+        ];
+        let (_, params) = decode(&Game::VCS, &params);
+        assert_eq!(params, ["0", "2", "1", "1.5", "10"]);
+    }
+
+    #[test]
+    fn decodes_shared_type_tags() {
+        let params = [
+            0x03, 0x07, 0x0A, b'A', 0x00, 0x0D, 0x6C, 0x01, 0x08, 0xCC, 0x05, 0x00,
+        ];
+        let (_, params) = decode(&Game::VCS, &params);
+        assert_eq!(
+            params,
+            ["7", "\"A\"", "lvar_1", "lvar_0(lvar_1,8)", "gvar_5"]
+        );
+    }
+
+    #[test]
+    fn rejects_unassigned_type_tag() {
+        let (name, _) = decode(&Game::VCS, &[0x0B, 0x00]);
+        assert_eq!(name, INVALID_OPCODE);
+    }
+}
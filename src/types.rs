@@ -1,39 +1,205 @@
 use itertools::Itertools;
-use std::{str, fmt::{Formatter, Display}};
+use serde::Serialize;
+use std::{
+    fmt::{Display, Formatter},
+    str,
+};
 
 pub const INVALID_OPCODE: &str = "invalid";
+/// Keyword of the data blocks, the bytes that could not be decoded as instructions
+pub const HEX_BLOCK: &str = "hex";
+/// Prefix of conditions with the negation bit (0x8000) set
+pub const NOT_PREFIX: &str = "NOT";
 
 pub type Opcode = u16;
 pub type ScriptChunk = Vec<u8>;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ScriptType {
     MAIN,
     MISSION,
     EXTERNAL,
+    CLEO,
+}
+
+/// Type of a variable as encoded by San Andreas, other games don't type their variables
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VarType {
+    Any,
+    Int,
+    Float,
+    ShortString,
+    LongString,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variable {
+    /// offset in bytes from the start of the variable space
+    Global(u16),
+    /// index of the local variable
+    Local(u16),
+}
+
+/// Platform independent view of a parameter used by the printers
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamValue {
+    /// end of the argument list
+    EOL,
+    Raw(u8),
+    Int(i32),
+    Float(f32),
+    /// fixed size string
+    String(String),
+    /// 16-byte or variable length string (San Andreas)
+    LongString(String),
+    /// jump target as encoded, negative values are relative to the script start
+    Offset(i32),
+    Model {
+        id: i32,
+        name: String,
+    },
+    Var(Variable, VarType),
+    Array {
+        var: Variable,
+        index: Variable,
+        size: u8,
+        element_type: VarType,
+    },
 }
 
 pub trait InstructionParam: std::fmt::Debug + std::fmt::Display + Send {
     fn to_string(&self) -> Option<String>;
     fn to_offset(&self) -> Option<i32>;
+    fn to_int(&self) -> Option<i32>;
+    fn to_global(&self) -> Option<u16>;
+    fn value(&self) -> ParamValue;
+    /// name of the type tag the param was encoded with, e.g. NUM8 or FLOAT16
+    fn data_type(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+/// Decoded param with the type tag it was read with, the platform params
+/// don't tell apart the sizes of the same value (NUM8, NUM16, NUM32)
+#[derive(Debug)]
+pub struct TaggedParam {
+    pub data_type: &'static str,
+    pub param: Box<dyn InstructionParam>,
+}
+
+impl Display for TaggedParam {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.param.fmt(f)
+    }
+}
+
+impl InstructionParam for TaggedParam {
+    fn to_string(&self) -> Option<String> {
+        InstructionParam::to_string(self.param.as_ref())
+    }
+    fn to_offset(&self) -> Option<i32> {
+        self.param.to_offset()
+    }
+    fn to_int(&self) -> Option<i32> {
+        self.param.to_int()
+    }
+    fn to_global(&self) -> Option<u16> {
+        self.param.to_global()
+    }
+    fn value(&self) -> ParamValue {
+        self.param.value()
+    }
+    fn data_type(&self) -> Option<&'static str> {
+        Some(self.data_type)
+    }
+}
+
+/// Negative model id resolved with the model names table of the header
+#[derive(Debug)]
+pub struct ModelParam {
+    pub id: i32,
+    pub name: String,
+    /// type tag of the replaced integer param
+    pub data_type: Option<&'static str>,
+}
+
+impl Display for ModelParam {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{}", self.name)
+    }
+}
+
+impl InstructionParam for ModelParam {
+    fn to_string(&self) -> Option<String> {
+        None
+    }
+    fn to_offset(&self) -> Option<i32> {
+        None
+    }
+    fn to_int(&self) -> Option<i32> {
+        Some(self.id)
+    }
+    fn to_global(&self) -> Option<u16> {
+        None
+    }
+    fn value(&self) -> ParamValue {
+        ParamValue::Model {
+            id: self.id,
+            name: self.name.clone(),
+        }
+    }
+    fn data_type(&self) -> Option<&'static str> {
+        self.data_type
+    }
 }
 
 pub struct Instruction {
+    /// opcode as encoded, including the negation bit
     pub opcode: Opcode,
     pub name: String,
+    /// condition with the negation bit (0x8000) set
+    pub negated: bool,
+    /// number of bytes the instruction takes in the script
+    pub size: u32,
     pub offset: u32,
     pub params: Vec<Box<dyn InstructionParam>>,
 }
 
+impl Instruction {
+    /// Negation bit (0x8000) set on a command that is not a condition, the game ignores it
+    pub fn has_stray_negation(&self) -> bool {
+        self.opcode != 0xFFFF && self.opcode & 0x8000 != 0 && !self.negated
+    }
+}
+
+impl Instruction {
+    fn write(&self, f: &mut Formatter<'_>, raw_opcode: bool) -> std::fmt::Result {
+        write!(f, "{{{:>0width$}}} ", self.offset, width = 6)?;
+        if self.negated {
+            write!(f, "{} ", NOT_PREFIX)?;
+        }
+        if raw_opcode {
+            write!(f, "{:04X}: ", self.opcode)?;
+        }
+        write!(f, "{} {}", self.name, self.params.iter().join(" "))
+    }
+}
+
 impl Display for Instruction {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{{{:>0width$}}} {} {}",
-            self.offset,
-            self.name,
-            self.params.iter().join(" "),
-            width = 6
-        )
+        // the raw opcode keeps the bit for the assembler
+        self.write(f, self.has_stray_negation())
+    }
+}
+
+/// Writes the instruction with its raw opcode (`0A93: TERMINATE_THIS_CUSTOM_SCRIPT`)
+/// for the assembler to tell apart the commands sharing a name
+pub struct RawOpcode<'a>(pub &'a Instruction);
+
+impl Display for RawOpcode<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.write(f, true)
     }
 }
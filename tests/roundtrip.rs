@@ -0,0 +1,52 @@
+//! Disassembling the original scripts and assembling the text back gives the same bytes
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn run(args: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_crust"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(args)
+        .output()
+        .expect("Can't run crust");
+    assert!(
+        output.status.success(),
+        "crust {} failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+fn roundtrip(game: &str) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("roundtrip_{}", game));
+    let source = dir.join("out");
+    let compiled = dir.join(format!("{}.scm", game));
+    let input = format!("scm/{}.scm", game);
+    let defs = format!("{}.json", game);
+
+    // --force only clears the files of a run it knows, start from scratch instead
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let out = source.to_str().unwrap();
+    run(&[&input, &defs, "--game", game, "--out", out, "--force"]);
+    let assemble = compiled.to_str().unwrap();
+    run(&[out, &defs, "--game", game, "--assemble", assemble]);
+
+    let original = fs::read(root.join(&input)).unwrap();
+    let rebuilt = fs::read(&compiled).unwrap();
+    assert_eq!(original.len(), rebuilt.len(), "{} size differs", game);
+    let first_difference = original.iter().zip(&rebuilt).position(|(a, b)| a != b);
+    assert_eq!(first_difference, None, "{} differs at the offset", game);
+}
+
+#[test]
+fn gta3() {
+    roundtrip("gta3");
+}
+
+#[test]
+fn vc() {
+    roundtrip("vc");
+}